@@ -0,0 +1,239 @@
+//! Async front end: the same connections as `TcpStream` and `TcpListener`, but instead of
+//! waiting on a condvar a task registers its `Waker` with the connection, and the packet
+//! thread wakes it once the connection becomes readable, writable or acceptable.
+//!
+//! Works with any executor, `crate::executor` has a minimal one.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{Interface, NewReno, TcpListener, TcpStream};
+
+pub struct AsyncTcpStream {
+    inner: TcpStream,
+}
+
+impl AsyncTcpStream {
+    /// Open a connection to `remote`, the future completes with the three-way handshake.
+    pub fn connect(
+        iface: &mut Interface,
+        remote: impl Into<SocketAddr>,
+    ) -> impl Future<Output = io::Result<AsyncTcpStream>> {
+        let stream = iface.start_connect(remote.into(), NewReno::boxed, std::time::Instant::now());
+        Connect { stream: Some(stream) }
+    }
+
+    /// the blocking stream underneath, for its options like `set_nodelay`
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.inner
+    }
+}
+
+impl From<TcpStream> for AsyncTcpStream {
+    fn from(inner: TcpStream) -> Self {
+        AsyncTcpStream { inner }
+    }
+}
+
+struct Connect {
+    /// taken once the future completed
+    stream: Option<io::Result<TcpStream>>,
+}
+
+impl Future for Connect {
+    type Output = io::Result<AsyncTcpStream>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stream = match self.stream.take().expect("polled after completion") {
+            Ok(stream) => stream,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let mut cm = stream.ih.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&stream.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        if let Some(e) = c.error() {
+            // refused, reset or timed out during the handshake, nobody holds this connection
+            cm.connections.remove(&stream.quad);
+            return Poll::Ready(Err(e));
+        }
+        if c.is_synchronized() {
+            drop(cm);
+            return Poll::Ready(Ok(stream.into()));
+        }
+        // woken when the handshake completes
+        c.write_waker = Some(cx.waker().clone());
+        drop(cm);
+        self.stream = Some(Ok(stream));
+        Poll::Pending
+    }
+}
+
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let stream = &self.inner;
+        let mut cm = stream.ih.manager.lock().unwrap();
+        match TcpStream::read_nonblocking(&mut cm, stream.quad, buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // still under the lock, so the packet thread can't have missed it
+                if let Some(c) = cm.connections.get_mut(&stream.quad) {
+                    c.read_waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let stream = &self.inner;
+        let mut cm = stream.ih.manager.lock().unwrap();
+        match TcpStream::write_nonblocking(&mut cm, stream.quad, buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(c) = cm.connections.get_mut(&stream.quad) {
+                    c.write_waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// ready once the peer has acked everything written so far
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = &self.inner;
+        let mut cm = stream.ih.manager.lock().unwrap();
+        match TcpStream::flush_nonblocking(&mut cm, stream.quad) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(c) = cm.connections.get_mut(&stream.quad) {
+                    c.write_waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// sends our FIN like `shutdown`, without waiting for the peer's
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.shutdown(std::net::Shutdown::Write))
+    }
+}
+
+pub struct AsyncTcpListener {
+    inner: TcpListener,
+}
+
+impl AsyncTcpListener {
+    pub fn bind(iface: &mut Interface, port: u16) -> io::Result<Self> {
+        iface.bind(port).map(Self::from)
+    }
+
+    /// the blocking listener underneath, for its options like `set_congestion_control`
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.inner
+    }
+
+    /// Ready with the next established connection, the `Stream` impl yields the same.
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<AsyncTcpStream>> {
+        let listener = &self.inner;
+        let mut cm = listener.ih.manager.lock().unwrap();
+        match listener.accept_nonblocking(&mut cm) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                cm.listeners
+                    .get_mut(&listener.port)
+                    .expect("port closed while listener still active")
+                    .accept_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            result => Poll::Ready(result.map(AsyncTcpStream::from)),
+        }
+    }
+}
+
+impl From<TcpListener> for AsyncTcpListener {
+    fn from(inner: TcpListener) -> Self {
+        AsyncTcpListener { inner }
+    }
+}
+
+/// never ends, like `TcpListener::incoming`
+impl Stream for AsyncTcpListener {
+    type Item = io::Result<AsyncTcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_accept(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::LocalExecutor;
+    use crate::ChannelDevice;
+    use std::future::poll_fn;
+    use std::net::Ipv4Addr;
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    async fn read_to_end(stream: &mut AsyncTcpStream) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = poll_fn(|cx| Pin::new(&mut *stream).poll_read(cx, &mut buf)).await?;
+            if n == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    async fn write_all(stream: &mut AsyncTcpStream, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = poll_fn(|cx| Pin::new(&mut *stream).poll_write(cx, buf)).await?;
+            buf = &buf[n..];
+        }
+        poll_fn(|cx| Pin::new(&mut *stream).poll_flush(cx)).await
+    }
+
+    #[test]
+    fn echo_on_a_single_thread() {
+        let (a, b) = ChannelDevice::pair();
+        let mut a = Interface::with_device(a, A).unwrap();
+        let mut b = Interface::with_device(b, B).unwrap();
+        let mut listener = AsyncTcpListener::bind(&mut b, 8000).unwrap();
+
+        let mut executor = LocalExecutor::new();
+        executor.spawn(async move {
+            let mut stream = poll_fn(|cx| Pin::new(&mut listener).poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let data = read_to_end(&mut stream).await.unwrap();
+            write_all(&mut stream, &data).await.unwrap();
+            poll_fn(|cx| Pin::new(&mut stream).poll_close(cx)).await.unwrap();
+        });
+
+        // several times the send buffer, so writes have to wait for ACKs too
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let echoed = executor.block_on(async {
+            let mut stream = AsyncTcpStream::connect(&mut a, (B, 8000)).await?;
+            write_all(&mut stream, &data).await?;
+            poll_fn(|cx| Pin::new(&mut stream).poll_close(cx)).await?;
+            read_to_end(&mut stream).await
+        });
+        assert_eq!(echoed.unwrap(), data);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Whatever carries raw IP packets in and out of the stack.
+///
+/// The packet thread owns the device; it waits for readiness with `poll_recv`
+/// so that it can run the retransmission timers in between packets.
+pub trait PacketDevice: Send {
+    /// send one IP packet
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+    /// receive one IP packet, blocking until one arrives
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    /// wait at most `timeout` for a packet, returns true if `recv` won't block
+    fn poll_recv(&mut self, timeout: Duration) -> io::Result<bool>;
+}
+
+/// A tun device without packet info, so every frame is a bare IP packet.
+pub struct TunDevice {
+    iface: tun_tap::Iface,
+}
+
+impl TunDevice {
+    /// open the tun device `name`, this needs CAP_NET_ADMIN
+    pub fn new(name: &str) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tun)?;
+        Ok(TunDevice { iface })
+    }
+}
+
+impl PacketDevice for TunDevice {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.iface.send(buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.iface.recv(buf)
+    }
+
+    fn poll_recv(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut pfd = [nix::poll::PollFd::new(
+            self.iface.as_raw_fd(),
+            nix::poll::EventFlags::POLLIN,
+        )];
+        // rounded up, a wait under 1ms must not become a poll that returns right away
+        let millis = std::cmp::min(timeout.as_micros().div_ceil(1000), i32::MAX as u128) as i32;
+        let n = nix::poll::poll(&mut pfd[..], millis)
+            .map_err(|e| e.as_errno().unwrap())?;
+        assert_ne!(n, -1);
+        Ok(n > 0)
+    }
+}
+
+/// One end of an in-memory link, see `ChannelDevice::pair`.
+pub struct ChannelDevice {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+    /// packet already taken off the channel by `poll_recv`
+    ready: Option<Vec<u8>>,
+}
+
+impl ChannelDevice {
+    /// Two devices wired back to back: whatever one sends, the other receives.
+    ///
+    /// This lets two `Interface`s talk to each other inside one process without a tun device.
+    pub fn pair() -> (ChannelDevice, ChannelDevice) {
+        let (atx, brx) = mpsc::channel();
+        let (btx, arx) = mpsc::channel();
+        (
+            ChannelDevice {
+                tx: atx,
+                rx: arx,
+                ready: None,
+            },
+            ChannelDevice {
+                tx: btx,
+                rx: brx,
+                ready: None,
+            },
+        )
+    }
+}
+
+impl PacketDevice for ChannelDevice {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // like a cable with nothing plugged in on the other side, the packet is just lost
+        let _ = self.tx.send(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = match self.ready.take() {
+            Some(packet) => packet,
+            None => self.rx.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "other end of the link is gone")
+            })?,
+        };
+        let n = std::cmp::min(buf.len(), packet.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    fn poll_recv(&mut self, timeout: Duration) -> io::Result<bool> {
+        if self.ready.is_some() {
+            return Ok(true);
+        }
+        match self.rx.recv_timeout(timeout) {
+            Ok(packet) => {
+                self.ready = Some(packet);
+                Ok(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(false),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // nothing will ever arrive, but keep the timers running
+                thread::sleep(timeout);
+                Ok(false)
+            }
+        }
+    }
+}
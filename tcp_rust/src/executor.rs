@@ -0,0 +1,171 @@
+//! A minimal single-threaded executor, enough to drive the async front end in tests.
+//!
+//! Tasks run on the thread that calls `block_on`; wakers (from the packet thread, say)
+//! queue the task and unpark that thread.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// the id the future given to `block_on` is queued under
+const MAIN: usize = usize::MAX;
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// ids of the tasks that were woken and need another poll
+struct ReadyQueue {
+    ready: Mutex<VecDeque<usize>>,
+    thread: Thread,
+}
+
+struct TaskWaker {
+    id: usize,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.ready.lock().unwrap().push_back(self.id);
+        self.queue.thread.unpark();
+    }
+}
+
+pub struct LocalExecutor {
+    /// spawned tasks, `None` once they completed
+    tasks: Vec<Option<Task>>,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Default for LocalExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalExecutor {
+    pub fn new() -> Self {
+        LocalExecutor {
+            tasks: Vec::new(),
+            queue: Arc::new(ReadyQueue {
+                ready: Mutex::new(VecDeque::new()),
+                thread: thread::current(),
+            }),
+        }
+    }
+
+    /// Run `task` alongside the future of the next `block_on`, and any later ones until it completes.
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        let id = self.tasks.len();
+        self.tasks.push(Some(Box::pin(task)));
+        self.queue.ready.lock().unwrap().push_back(id);
+    }
+
+    fn waker(&self, id: usize) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            id,
+            queue: self.queue.clone(),
+        }))
+    }
+
+    /// Poll `future` and the spawned tasks whenever they are woken, until `future` completes.
+    ///
+    /// Must be called on the thread that created the executor, that's the one wakers unpark.
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        assert_eq!(thread::current().id(), self.queue.thread.id(), "block_on from another thread");
+        let mut future = std::pin::pin!(future);
+        self.queue.ready.lock().unwrap().push_back(MAIN);
+        loop {
+            let next = self.queue.ready.lock().unwrap().pop_front();
+            let Some(id) = next else {
+                // a wake that came in before this returns right away
+                thread::park();
+                continue;
+            };
+            let waker = self.waker(id);
+            let mut cx = Context::from_waker(&waker);
+            if id == MAIN {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            } else if let Some(task) = &mut self.tasks[id] {
+                if task.as_mut().poll(&mut cx).is_ready() {
+                    self.tasks[id] = None;
+                }
+            }
+        }
+    }
+}
+
+/// Run `future` to completion on the current thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    LocalExecutor::new().block_on(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// pending until another thread wakes it after `delay`
+    struct Sleep {
+        delay: Duration,
+        started: bool,
+        done: Arc<Mutex<bool>>,
+    }
+
+    impl Future for Sleep {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if *self.done.lock().unwrap() {
+                return Poll::Ready(());
+            }
+            if !self.started {
+                self.started = true;
+                let (delay, done, waker) = (self.delay, self.done.clone(), cx.waker().clone());
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    *done.lock().unwrap() = true;
+                    waker.wake();
+                });
+            }
+            Poll::Pending
+        }
+    }
+
+    fn sleep(delay: Duration) -> Sleep {
+        Sleep {
+            delay,
+            started: false,
+            done: Default::default(),
+        }
+    }
+
+    #[test]
+    fn spawned_tasks_run_while_blocking_on_another() {
+        let mut executor = LocalExecutor::new();
+        let finished = Rc::new(Cell::new(false));
+        {
+            let finished = finished.clone();
+            executor.spawn(async move {
+                sleep(Duration::from_millis(10)).await;
+                finished.set(true);
+            });
+        }
+        let output = executor.block_on(async {
+            sleep(Duration::from_millis(50)).await;
+            7
+        });
+        assert_eq!(output, 7);
+        assert!(finished.get());
+    }
+}
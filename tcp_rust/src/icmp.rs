@@ -0,0 +1,127 @@
+//! ICMPv4 (RFC 792): answering pings, telling senders that nobody here takes their
+//! datagrams, and reading the errors that come back about our own segments.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use etherparse::icmpv4::DestUnreachableHeader;
+use etherparse::{Icmpv4Header, Icmpv4Slice, Icmpv4Type, IpNumber, Ipv4Header, Ipv4Slice};
+
+use crate::device::PacketDevice;
+
+/// what an ICMP error means for the connection that sent the segment it quotes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IcmpError {
+    /// the peer's protocol or port is unreachable, which won't change (RFC 1122 S4.2.3.9)
+    Unreachable,
+    /// a router on the path can't forward datagrams larger than `mtu` (RFC 1191)
+    FragmentationNeeded { mtu: u16 },
+}
+
+/// An ICMP error about a TCP segment we sent, with the segment's addresses and
+/// sequence number.
+pub(crate) struct SegmentError {
+    pub(crate) local: SocketAddr,
+    pub(crate) remote: SocketAddr,
+    pub(crate) seq: u32,
+    pub(crate) error: IcmpError,
+}
+
+/// Handle an ICMP message that arrived in `ip`: answer an echo request, and return what
+/// an error says about a segment of ours, if anything.
+pub(crate) fn on_icmp(nic: &mut dyn PacketDevice, ip: &Ipv4Slice) -> io::Result<Option<SegmentError>> {
+    let Ok(icmp) = Icmpv4Slice::from_slice(ip.payload().payload) else {
+        return Ok(None);
+    };
+    if Icmpv4Header::with_checksum(icmp.icmp_type(), icmp.payload()).checksum != icmp.checksum() {
+        eprintln!("BAD ICMP CHECKSUM");
+        return Ok(None);
+    }
+
+    let error = match icmp.icmp_type() {
+        Icmpv4Type::EchoRequest(echo) => {
+            // answering every host that got it would multiply the traffic (RFC 1122 S3.2.2.6)
+            if not_for_us_alone(ip) {
+                return Ok(None);
+            }
+            // the reply carries the identifier, sequence number and data back
+            let (src, dst) = (ip.header().source(), ip.header().destination());
+            send(nic, dst, src, Icmpv4Type::EchoReply(echo), icmp.payload())?;
+            return Ok(None);
+        }
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Protocol | DestUnreachableHeader::Port) => {
+            IcmpError::Unreachable
+        }
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FragmentationNeeded { next_hop_mtu }) => {
+            IcmpError::FragmentationNeeded { mtu: next_hop_mtu }
+        }
+        // soft errors, the network may well recover before the connection times out
+        _ => return Ok(None),
+    };
+    Ok(quoted_segment(icmp.payload()).map(|(local, remote, seq)| SegmentError {
+        local,
+        remote,
+        seq,
+        error,
+    }))
+}
+
+/// The addresses and sequence number of the TCP segment an ICMP error quotes: its IP
+/// header and at least the first 8 bytes of its TCP header follow the ICMP header.
+fn quoted_segment(quote: &[u8]) -> Option<(SocketAddr, SocketAddr, u32)> {
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(quote).ok()?;
+    if iph.protocol() != IpNumber::TCP {
+        return None;
+    }
+    let tcp = quote.get(iph.slice().len()..iph.slice().len() + 8)?;
+    let src_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let dst_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+    Some((
+        SocketAddr::new(iph.source_addr().into(), src_port),
+        SocketAddr::new(iph.destination_addr().into(), dst_port),
+        seq,
+    ))
+}
+
+/// Tell the sender of the datagram in `ip` that nobody here takes it, `code` says why.
+pub(crate) fn send_unreachable(
+    nic: &mut dyn PacketDevice,
+    ip: &Ipv4Slice,
+    code: DestUnreachableHeader,
+) -> io::Result<()> {
+    // not about datagrams sent to many hosts at once (RFC 1122 S3.2.2)
+    if not_for_us_alone(ip) {
+        return Ok(());
+    }
+    // the datagram's IP header and the first 8 bytes of what it carried
+    let payload = ip.payload().payload;
+    let mut quote = ip.header().slice().to_vec();
+    quote.extend_from_slice(&payload[..std::cmp::min(8, payload.len())]);
+    let (src, dst) = (ip.header().source(), ip.header().destination());
+    send(nic, dst, src, Icmpv4Type::DestinationUnreachable(code), &quote)
+}
+
+/// whether the datagram in `ip` went to many hosts at once, or came from nobody in particular
+fn not_for_us_alone(ip: &Ipv4Slice) -> bool {
+    let dst = ip.header().destination_addr();
+    dst.is_broadcast() || dst.is_multicast() || ip.header().source_addr() == Ipv4Addr::UNSPECIFIED
+}
+
+fn send(
+    nic: &mut dyn PacketDevice,
+    src: [u8; 4],
+    dst: [u8; 4],
+    icmp_type: Icmpv4Type,
+    payload: &[u8],
+) -> io::Result<()> {
+    let icmp = Icmpv4Header::with_checksum(icmp_type, payload);
+    let len = icmp.header_len() + payload.len();
+    let ip = Ipv4Header::new(len as u16, 64, IpNumber::ICMP, src, dst).unwrap();
+    let mut buf = Vec::with_capacity(ip.header_len() + len);
+    ip.write(&mut buf)?;
+    icmp.write(&mut buf)?;
+    buf.extend_from_slice(payload);
+    nic.send(&buf)?;
+    Ok(())
+}
@@ -1,11 +1,13 @@
-use std::collections::hash_map::Entry;
-use std::collections::{HashMap, VecDeque};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::prelude::*;
 use std::{io, thread};
-use std::sync::{Arc, Condvar, Mutex};
-use std::net::Ipv4Addr;
-use etherparse::IpNumber;
-use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::Waker;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use etherparse::icmpv4::DestUnreachableHeader;
+use etherparse::{IpNumber, IpSlice};
 
 // impl design:
 // 1. a seperate thread hold  the nic to read
@@ -15,14 +17,39 @@ use std::os::unix::io::AsRawFd;
 //    - copy_from_slice() from the head and tail
 // 4. on_tick() to process the retransmission packet, use srtt to determin if need retrans
 
+mod async_io;
+mod device;
+pub mod executor;
+mod icmp;
+pub mod sim;
 mod tcp;
+mod udp;
 
-const SENDQUEUE_SIZE: usize = 1024;
+pub use async_io::{AsyncTcpListener, AsyncTcpStream};
+pub use device::{ChannelDevice, PacketDevice, TunDevice};
+pub use tcp::congestion::{CongestionControl, CongestionFactory, Cubic, NewReno};
+pub use tcp::Keepalive;
+pub use udp::UdpSocket;
 
-#[derive(Debug,Clone,Copy,Eq,Hash,PartialEq)]
+/// default of how many bytes a connection buffers until they are acked, `write` blocks beyond this
+const SENDQUEUE_SIZE: usize = 64 * 1024;
+/// default size of the receive buffer of every connection
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+/// address used for active opens when none is given, inside the 192.168.3.0/24 net of run.sh
+const DEFAULT_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 3, 2);
+/// IANA dynamic port range (RFC 6335) that `connect` picks local ports from
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+/// how long `connect` waits for the handshake to complete
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(75);
+/// how many connections may wait in each queue of a listener bound with `bind`
+const DEFAULT_BACKLOG: usize = 128;
+/// how long the packet thread waits for a packet before running the timers
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug,Clone,Copy,Eq,Hash,PartialEq,Ord,PartialOrd)]
 struct Quad {
-    src: (Ipv4Addr,u16),
-    dst: (Ipv4Addr,u16),
+    src: SocketAddr,
+    dst: SocketAddr,
 }
 
 #[derive(Default)]
@@ -30,17 +57,132 @@ struct TcpHandle {
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
     rcv_var: Condvar,
+    /// woken when ACKs free room in a send queue or drain it
+    snd_var: Condvar,
+}
+
+/// a bound port
+struct Listener {
+    /// half-open connections, still in the handshake
+    syn_queue: VecDeque<Quad>,
+    /// established connections waiting to be accepted
+    pending: VecDeque<Quad>,
+    /// how long either queue may get, SYNs beyond that are dropped
+    backlog: usize,
+    /// congestion control of the connections accepted on this port
+    congestion: CongestionFactory,
+    /// `accept` fails with `WouldBlock` instead of waiting
+    nonblocking: bool,
+    /// the task of the async front end waiting for a connection
+    accept_waker: Option<Waker>,
 }
 
-#[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
-    connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    // ordered, so that timers run in the same order on every (simulated) run
+    connections: BTreeMap<Quad, tcp::Connection>,
+    listeners: BTreeMap<u16, Listener>,
+    /// bound UDP ports, a namespace of their own
+    udp: BTreeMap<u16, udp::Endpoint>,
+    next_ephemeral: u16,
+    /// receive buffer size given to new connections
+    recv_buffer: usize,
+    /// send buffer size given to new connections
+    send_buffer: usize,
+    /// whether new connections offer selective acknowledgements
+    sack: bool,
+    /// initial sequence numbers of new connections
+    iss: tcp::iss::IssGenerator,
+    /// answer SYNs with SYN cookies once a SYN queue is full
+    syn_cookies: Option<tcp::cookie::SynCookies>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager {
+            terminate: false,
+            connections: Default::default(),
+            listeners: Default::default(),
+            udp: Default::default(),
+            next_ephemeral: 0,
+            recv_buffer: RECV_BUFFER_SIZE,
+            send_buffer: SENDQUEUE_SIZE,
+            sack: true,
+            iss: tcp::iss::IssGenerator::new(tcp::random_key(), Instant::now()),
+            syn_cookies: None,
+        }
+    }
+}
+
+impl ConnectionManager {
+    /// pick a local port from `EPHEMERAL_PORTS` that is neither bound nor in use towards `remote`
+    fn ephemeral_quad(&mut self, local: IpAddr, remote: SocketAddr) -> io::Result<Quad> {
+        let first = *EPHEMERAL_PORTS.start();
+        let span = EPHEMERAL_PORTS.end() - first + 1;
+        for _ in 0..span {
+            let port = first + self.next_ephemeral % span;
+            self.next_ephemeral = self.next_ephemeral.wrapping_add(1);
+            let quad = Quad {
+                src: remote,
+                dst: SocketAddr::new(local, port),
+            };
+            if !self.listeners.contains_key(&port) && !self.connections.contains_key(&quad) {
+                return Ok(quad);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no free ephemeral port",
+        ))
+    }
+
+    /// pick a port from `EPHEMERAL_PORTS` that no UDP socket is bound to
+    fn ephemeral_udp_port(&mut self) -> io::Result<u16> {
+        let first = *EPHEMERAL_PORTS.start();
+        let span = EPHEMERAL_PORTS.end() - first + 1;
+        for _ in 0..span {
+            let port = first + self.next_ephemeral % span;
+            self.next_ephemeral = self.next_ephemeral.wrapping_add(1);
+            if !self.udp.contains_key(&port) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no free ephemeral port",
+        ))
+    }
 }
 
 type InterfaceHandle = Arc<TcpHandle>;
 
+/// Wait on `var` until it is notified or `deadline` passes, `TimedOut` once it has.
+fn wait_until<'a>(
+    var: &Condvar,
+    cm: MutexGuard<'a, ConnectionManager>,
+    deadline: Option<Instant>,
+) -> io::Result<MutexGuard<'a, ConnectionManager>> {
+    let Some(deadline) = deadline else {
+        return Ok(var.wait(cm).unwrap());
+    };
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "operation timed out"));
+    }
+    Ok(var.wait_timeout(cm, deadline - now).unwrap().0)
+}
+
+/// `std::net` refuses a zero timeout, it would be a poll that can't be told from a timeout
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
 pub struct TcpStream {
     quad: Quad,
     ih: InterfaceHandle,
@@ -48,9 +190,21 @@ pub struct TcpStream {
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let cm = self.ih.manager.lock().unwrap();
-        // TODO: send FIN on cm.connections[quad]
-        // TODO: _eventually_ remove self.quad from cm.connections
+        // don't panic again if a panic poisoned the lock
+        let Ok(mut cm) = self.ih.manager.lock() else {
+            return;
+        };
+        let Some(c) = cm.connections.get_mut(&self.quad) else {
+            return;
+        };
+        c.detached = true;
+        c.incoming.clear();
+        if c.is_closed() {
+            cm.connections.remove(&self.quad);
+        } else {
+            // the closing handshake runs on its own, on_tick forgets the connection once it's over
+            let _ = c.close();
+        }
     }
 }
 
@@ -58,82 +212,143 @@ impl Drop for TcpStream {
 impl Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut cm = self.ih.manager.lock().unwrap();
+        let (nonblocking, deadline) = cm.connections.get(&self.quad).map_or((false, None), |c| {
+            (c.nonblocking, c.read_timeout.map(|t| Instant::now() + t))
+        });
         loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "stream was terminated unexpectedly",)
-            })?;
-
-            if c.is_rcv_closed() && c.incoming.is_empty() {
-                // no more data to read, and no need to block, because there won't be any more
-                return Ok(0);
-            }
-
-            if !c.incoming.is_empty() {
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
-                return Ok(nread);
+            match Self::read_nonblocking(&mut cm, self.quad, buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !nonblocking => {}
+                result => return result,
             }
 
-            cm = self.ih.rcv_var.wait(cm).unwrap();
+            cm = wait_until(&self.ih.rcv_var, cm, deadline)?;
         }
     }
 }
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut cm = self.ih.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+impl TcpStream {
+    /// whether `write` and `flush` may wait, and until when
+    fn write_options(cm: &ConnectionManager, quad: Quad) -> (bool, Option<Instant>) {
+        cm.connections.get(&quad).map_or((false, None), |c| {
+            (c.nonblocking, c.write_timeout.map(|t| Instant::now() + t))
+        })
+    }
+
+    /// queue as much of `buf` as fits, `WouldBlock` if nothing does
+    fn write_nonblocking(cm: &mut ConnectionManager, quad: Quad, buf: &[u8]) -> io::Result<usize> {
+        let c = cm.connections.get_mut(&quad).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::ConnectionAborted,
                 "stream was terminated unexpectedly",
             )
         })?;
 
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
+        if let Some(e) = c.error() {
+            return Err(e);
+        }
+
+        if c.unacked.len() >= c.send_buffer {
             return Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
                 "too many bytes buffered",
             ));
         }
 
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
+        let nwrite = std::cmp::min(buf.len(), c.send_buffer - c.unacked.len());
         c.unacked.extend(buf[..nwrite].iter());
 
         Ok(nwrite)
     }
-    fn flush(&mut self) -> io::Result<()> {
-        let mut cm = self.ih.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+
+    /// one attempt at `flush` that returns `WouldBlock` while data is still unacked
+    fn flush_nonblocking(cm: &mut ConnectionManager, quad: Quad) -> io::Result<()> {
+        let c = cm.connections.get_mut(&quad).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::ConnectionAborted,
                 "stream was terminated unexpectedly",
             )
         })?;
 
+        if let Some(e) = c.error() {
+            return Err(e);
+        }
+
         if c.unacked.is_empty() {
             Ok(())
         } else {
-            // TODO: block
             Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
+                "unacked bytes buffered",
             ))
         }
     }
+
+    /// one attempt at `read` that returns `WouldBlock` instead of waiting for data
+    fn read_nonblocking(cm: &mut ConnectionManager, quad: Quad, buf: &mut [u8]) -> io::Result<usize> {
+        let c = cm.connections.get_mut(&quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",)
+        })?;
+
+        if let Some(e) = c.error() {
+            return Err(e);
+        }
+
+        if c.is_rcv_closed() && c.incoming.is_empty() {
+            // no more data to read, and no need to block, because there won't be any more
+            return Ok(0);
+        }
+
+        if !c.incoming.is_empty() {
+            let mut nread = 0;
+            let (head, tail) = c.incoming.as_slices();
+            let hread = std::cmp::min(buf.len(), head.len());
+            buf[..hread].copy_from_slice(&head[..hread]);
+            nread += hread;
+            let tread = std::cmp::min(buf.len() - nread, tail.len());
+            buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
+            nread += tread;
+            drop(c.incoming.drain(..nread));
+            // the packet thread sends a window update on its next tick if this opened the window
+            return Ok(nread);
+        }
+
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no data received yet"))
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        let (nonblocking, deadline) = Self::write_options(&cm, self.quad);
+        loop {
+            match Self::write_nonblocking(&mut cm, self.quad, buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !nonblocking => {}
+                result => return result,
+            }
+
+            cm = wait_until(&self.ih.snd_var, cm, deadline)?;
+        }
+    }
+
+    /// block until the peer has acked everything written so far
+    fn flush(&mut self) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        let (nonblocking, deadline) = Self::write_options(&cm, self.quad);
+        loop {
+            match Self::flush_nonblocking(&mut cm, self.quad) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !nonblocking => {}
+                result => return result,
+            }
+
+            cm = wait_until(&self.ih.snd_var, cm, deadline)?;
+        }
+    }
 }
 
 impl TcpStream {
-    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+    pub fn shutdown(&self, _how: std::net::Shutdown) -> io::Result<()> {
         let mut cm = self.ih.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -144,121 +359,439 @@ impl TcpStream {
 
         c.close()
     }
+
+    /// Turn Nagle's algorithm off (or back on): small writes go out right away instead
+    /// of waiting for the data in flight to be acked.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        c.nodelay = nodelay;
+        Ok(())
+    }
+
+    /// Probe the peer once the connection has been idle for a while, and abort with
+    /// `TimedOut` if it doesn't answer; `None` turns that off again, which is the default.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        c.keepalive = keepalive;
+        Ok(())
+    }
+
+    pub fn keepalive(&self) -> io::Result<Option<Keepalive>> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        Ok(c.keepalive)
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        Ok(c.nodelay)
+    }
+
+    /// Make `read` fail with `TimedOut` once it waited `timeout` for data, `None` waits
+    /// forever, which is the default. A zero timeout is `InvalidInput`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        c.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Make `write` and `flush` fail with `TimedOut` once they waited `timeout` for the
+    /// peer to ack, `None` waits forever, which is the default. A zero timeout is `InvalidInput`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        c.write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        Ok(c.read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        Ok(c.write_timeout)
+    }
+
+    /// Make `read`, `write` and `flush` fail with `WouldBlock` instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        c.nonblocking = nonblocking;
+        Ok(())
+    }
 }
 pub struct Interface {
     ih: Option<InterfaceHandle>,
     jh: Option<thread::JoinHandle<io::Result<()>>>,
+    addr: Ipv4Addr,
+    /// local address of connections to IPv6 peers, they can't be opened without one
+    addr6: Option<Ipv6Addr>,
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
+fn packet_loop<D: PacketDevice>(mut nic: D, ih: InterfaceHandle) -> io::Result<()> {
     let mut buf = [0u8;1504];
+    let mut next_tick = Instant::now() + TICK;
     loop {
-        let mut pfd =[nix::poll::PollFd::new(
-            nic.as_raw_fd(),
-            nix::poll::EventFlags::POLLIN,
-        )];
-        let n = nix::poll::poll(&mut pfd[..],10).map_err(|e| e.as_errno().unwrap())?;
-        assert_ne!(n, -1);
-        if n == 0 {
-            let mut cmg = ih.manager.lock().unwrap();
-            for connection in cmg.connections.values_mut() {
-                // XXX: don't die on errors?
-                connection.on_tick(&mut nic)?;
+        // run the timers every TICK, even when packets keep arriving
+        let wait = next_tick.saturating_duration_since(Instant::now());
+        if wait.is_zero() || !nic.poll_recv(wait)? {
+            if ih.manager.lock().unwrap().terminate {
+                return Ok(());
             }
+            on_tick(&ih, &mut nic, Instant::now())?;
+            next_tick = Instant::now() + TICK;
             continue;
         }
-        assert_eq!(n, 1);
         // &mut buf[..] 显示传递数组切片
         let nbytes = nic.recv(&mut buf[..])?;
-        // netwotk endian is big endian
-
-        // tun iface /without_packet_info/new/:
-        
-        // let _eth_flags = u16::from_be_bytes([buf[0],buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2],buf[3]]);
-        // if eth_proto != 0x800 {
-        //     //not ipv4
-        //     continue;
-        // }
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
-                eprintln!("protocol: {:x}", iph.protocol().0);
-                
-                if iph.protocol() != IpNumber::TCP {
-                    //not tcp                        
-                    eprintln!("BAD PROTOCOL");       
-                    continue;                        
-                }                                    
-                
-
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        eprintln!("{} -> {} {}byte of tcp to port {}",
-                            src,dst,tcph.slice().len(),tcph.destination_port());
-                        let data = iph.slice().len() + tcph.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let cm = &mut *cmg;
-                        let q = Quad {
-                            src: (src, tcph.source_port()),
-                            dst: (dst, tcph.destination_port()),
-                        };
-
-                        match cm.connections.entry(q) {
-                            Entry::Occupied(mut c) => {
-                                  eprintln!("got packet for known quad {:?}", q);
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    iph,
-                                    tcph,
-                                    &buf[data..nbytes],
-                                )?;
-
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all()
-                                }
-                                if a.contains(tcp::Available::WRITE) {
-                                    // TODO: ih.snd_var.notify_all()
+        on_datagram(&ih, &mut nic, &buf[..nbytes], Instant::now())?;
+    }
+}
+
+/// run the (re)transmission timers of every connection
+fn on_tick(ih: &TcpHandle, nic: &mut dyn PacketDevice, now: Instant) -> io::Result<()> {
+    let mut cmg = ih.manager.lock().unwrap();
+    let mut aborted = false;
+    let mut wakers = Vec::new();
+    for connection in cmg.connections.values_mut() {
+        let had_error = connection.error().is_some();
+        // XXX: don't die on errors?
+        connection.on_tick(nic, now)?;
+        if !had_error && connection.error().is_some() {
+            aborted = true;
+            wakers.extend(connection.read_waker.take());
+            wakers.extend(connection.write_waker.take());
+        }
+    }
+    let cm = &mut *cmg;
+    for l in cm.listeners.values_mut() {
+        // forget connections reset or timed out before they could be accepted
+        let connections = &mut cm.connections;
+        let mut alive = |q: &Quad| match connections.get(q) {
+            Some(c) if !c.is_closed() => true,
+            _ => {
+                connections.remove(q);
+                false
+            }
+        };
+        l.syn_queue.retain(&mut alive);
+        l.pending.retain(&mut alive);
+    }
+    cm.connections.retain(|_, c| !(c.detached && c.is_closed()));
+    let sent = udp::on_tick(cm, nic);
+    drop(cmg);
+    if sent {
+        // room in the UDP send queues again
+        ih.snd_var.notify_all();
+    }
+    if aborted {
+        // a connection timed out, wake up whoever waits on it to report the error
+        ih.pending_var.notify_all();
+        ih.rcv_var.notify_all();
+        ih.snd_var.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+    Ok(())
+}
+
+/// hand an ICMP error to the connection that sent the segment it is about
+fn on_icmp_error(ih: &TcpHandle, nic: &mut dyn PacketDevice, e: icmp::SegmentError, now: Instant) -> io::Result<()> {
+    let mut cm = ih.manager.lock().unwrap();
+    let Some(c) = cm.connections.get_mut(&Quad { src: e.remote, dst: e.local }) else {
+        return Ok(());
+    };
+    let had_error = c.error().is_some();
+    c.on_icmp_error(nic, e.error, e.seq, now)?;
+    if !had_error && c.error().is_some() {
+        let wakers = [c.read_waker.take(), c.write_waker.take()];
+        drop(cm);
+        // aborted, wake up whoever waits on it to report the error
+        ih.pending_var.notify_all();
+        ih.rcv_var.notify_all();
+        ih.snd_var.notify_all();
+        wakers.into_iter().flatten().for_each(Waker::wake);
+    }
+    Ok(())
+}
+
+/// process one IP packet received from the nic
+fn on_datagram(ih: &TcpHandle, nic: &mut dyn PacketDevice, buf: &[u8], now: Instant) -> io::Result<()> {
+    // netwotk endian is big endian
+
+    // tun iface /without_packet_info/new/:
+    
+    // let _eth_flags = u16::from_be_bytes([buf[0],buf[1]]);
+    // let eth_proto = u16::from_be_bytes([buf[2],buf[3]]);
+    // if eth_proto != 0x800 {
+    //     //not ipv4
+    //     continue;
+    // }
+
+    // IPv4 or IPv6, with the extension headers of either skipped
+    match IpSlice::from_slice(buf) {
+        Ok(ip) => {
+            let src = ip.source_addr();
+            let dst = ip.destination_addr();
+            eprintln!("protocol: {:x}", ip.payload_ip_number().0);
+            
+            if let IpSlice::Ipv4(v4) = &ip {
+                // IPv6 has no header checksum
+                if v4.header().header_checksum() != v4.header().to_header().calc_header_checksum() {
+                    eprintln!("BAD IP CHECKSUM");
+                    return Ok(());
+                }
+            }
+
+            if ip.is_fragmenting_payload() {
+                // we don't reassemble, and announce an MSS that needs no fragmenting
+                eprintln!("FRAGMENTED PACKET");
+                return Ok(());
+            }
+
+            if let IpSlice::Ipv4(v4) = &ip {
+                if ip.payload_ip_number() == IpNumber::ICMP {
+                    if let Some(e) = icmp::on_icmp(nic, v4)? {
+                        on_icmp_error(ih, nic, e, now)?;
+                    }
+                    return Ok(());
+                }
+            }
+
+            if ip.payload_ip_number() == IpNumber::UDP {
+                return udp::on_udp(ih, nic, &ip);
+            }
+
+            if ip.payload_ip_number() != IpNumber::TCP {
+                //not tcp                        
+                eprintln!("BAD PROTOCOL");       
+                if let IpSlice::Ipv4(v4) = &ip {
+                    icmp::send_unreachable(nic, v4, DestUnreachableHeader::Protocol)?;
+                }
+                return Ok(());
+            }                                    
+            
+
+            let payload = ip.payload().payload;
+            match etherparse::TcpHeaderSlice::from_slice(payload) {
+                Ok(tcph) => {
+                    eprintln!("{} -> {} {}byte of tcp to port {}",
+                        src,dst,tcph.slice().len(),tcph.destination_port());
+                    let data = &payload[tcph.slice().len()..];
+                    if !tcp::checksum_ok(&ip, &tcph, data) {
+                        eprintln!("BAD TCP CHECKSUM");
+                        return Ok(());
+                    }
+
+                    let mut cmg = ih.manager.lock().unwrap();
+                    let cm = &mut *cmg;
+                    let q = Quad {
+                        src: SocketAddr::new(src, tcph.source_port()),
+                        dst: SocketAddr::new(dst, tcph.destination_port()),
+                    };
+
+                    match cm.connections.entry(q) {
+                        Entry::Occupied(mut c) => {
+                              eprintln!("got packet for known quad {:?}", q);
+                            let was_synchronized = c.get().is_synchronized();
+                            let a = c.get_mut().on_packet(
+                                nic,
+                                &ip,
+                                tcph,
+                                data,
+                                now,
+                            )?;
+                            let established = !was_synchronized && c.get().is_synchronized();
+                            // tasks of the async front end, woken once the lock is released
+                            let mut wakers = Vec::new();
+                            if a.contains(tcp::Available::READ) {
+                                wakers.extend(c.get_mut().read_waker.take());
+                            }
+                            if established || a.contains(tcp::Available::WRITE) {
+                                wakers.extend(c.get_mut().write_waker.take());
+                            }
+                            if established {
+                                // a passive open completed, it can be accepted now
+                                if let Some(l) = cm.listeners.get_mut(&q.dst.port()) {
+                                    if let Some(i) = l.syn_queue.iter().position(|&sq| sq == q) {
+                                        l.syn_queue.remove(i);
+                                        l.pending.push_back(q);
+                                        wakers.extend(l.accept_waker.take());
+                                    }
                                 }
                             }
 
-                            Entry::Vacant(e) => {
-                                eprintln!("got packet for unknown quad {:?}", q);
-                                if let Some(pending) = cm.pending.get_mut(&tcph.destination_port()) {
-                                    eprintln!("listening and begin accept");
-                                    if let Some(c) = tcp::Connection::accept(&mut nic, iph, tcph, &buf[data..nbytes])? {
-                                        e.insert(c);
-                                        pending.push_back(q);
-                                        drop(cmg);
-                                        ih.pending_var.notify_all()
+                            // TODO: compare before/after
+                            drop(cmg);
+                            if established {
+                                // wake up connect() callers waiting for the handshake
+                                ih.pending_var.notify_all()
+                            }
+                            if a.contains(tcp::Available::READ) {
+                                ih.rcv_var.notify_all()
+                            }
+                            if a.contains(tcp::Available::WRITE) {
+                                ih.snd_var.notify_all()
+                            }
+                            wakers.into_iter().for_each(Waker::wake);
+                        }
+
+                        Entry::Vacant(e) => {
+                            eprintln!("got packet for unknown quad {:?}", q);
+                            if let Some(l) = cm.listeners.get_mut(&tcph.destination_port()) {
+                                eprintln!("listening and begin accept");
+                                let config = tcp::Config {
+                                    recv_buffer: cm.recv_buffer,
+                                    send_buffer: cm.send_buffer,
+                                    congestion: l.congestion,
+                                    sack: cm.sack,
+                                };
+                                let accept_full = l.pending.len() >= l.backlog;
+                                if tcph.syn() && (l.syn_queue.len() >= l.backlog || accept_full) {
+                                    match &cm.syn_cookies {
+                                        Some(cookies) if !accept_full && !tcph.ack() && !tcph.rst() => {
+                                            // the SYN queue is full, answer without keeping any state
+                                            tcp::Connection::send_syn_cookie(nic, &ip, tcph, config, cookies, now)?;
+                                        }
+                                        // no room, the peer will retry its SYN
+                                        _ => eprintln!("listen queue full, dropping SYN"),
+                                    }
+                                    return Ok(());
+                                }
+                                if let Some(cookies) = &cm.syn_cookies {
+                                    if tcph.ack() && !tcph.syn() && !tcph.rst() {
+                                        if accept_full {
+                                            // it may well be valid, so don't reset it; the peer
+                                            // retransmits its data and gets in once there's room
+                                            eprintln!("accept queue full, dropping ACK");
+                                            return Ok(());
+                                        }
+                                        // maybe the final ACK of a handshake we answered with a cookie
+                                        let c = tcp::Connection::from_syn_cookie(
+                                            nic,
+                                            &ip,
+                                            tcph.clone(),
+                                            data,
+                                            config,
+                                            cookies,
+                                            now,
+                                        )?;
+                                        if let Some(c) = c {
+                                            e.insert(c);
+                                            l.pending.push_back(q);
+                                            let waker = l.accept_waker.take();
+                                            drop(cmg);
+                                            ih.pending_var.notify_all();
+                                            waker.into_iter().for_each(Waker::wake);
+                                            return Ok(());
+                                        }
                                     }
-                                }else {
-                                    eprintln!("None in the pending queue");
                                 }
+                                let iss = cm.iss.generate(q.dst, q.src, now);
+                                if let Some(c) = tcp::Connection::accept(nic, &ip, tcph, data, iss, config, now)? {
+                                    e.insert(c);
+                                    l.syn_queue.push_back(q);
+                                }
+                            }else {
+                                eprintln!("None in the pending queue");
+                                tcp::send_rst(nic, &ip, &tcph, data.len())?;
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("igonring weird tcp packet {:?}",e);
-                    }
-                  
                 }
+                Err(e) => {
+                    eprintln!("igonring weird tcp packet {:?}",e);
+                }
+              
             }
-            Err(e) => {
-              //  eprintln!("igonring weird packet {:?}",e);
-            }
         }
+        Err(_e) => {
+          //  eprintln!("igonring weird packet {:?}",e);
+        }
+    }
 //        eprintln!("byte flags {:x}, proto {:x}",_eth_flags, eth_proto);
-        //eprintln!("read {} bytes: {:x?}", nbytes, buf);
+    //eprintln!("read {} bytes: {:x?}", nbytes, buf);
+    Ok(())
+}
+/// of `addr` and `addr6`, the one to talk to `remote` from
+fn local_addr(addr: Ipv4Addr, addr6: Option<Ipv6Addr>, remote: IpAddr) -> io::Result<IpAddr> {
+    match remote {
+        IpAddr::V4(_) => Ok(addr.into()),
+        IpAddr::V6(_) => addr6.map(IpAddr::from).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no IPv6 address to connect from",
+            )
+        }),
     }
 }
+
 impl Interface {
     pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0",tun_tap::Mode::Tun)?;
+        Self::with_addr(DEFAULT_ADDR)
+    }
+
+    /// like `new`, but use `addr` as the local address of connections opened with `connect`
+    pub fn with_addr(addr: Ipv4Addr) -> io::Result<Self> {
+        Self::with_device(TunDevice::new("tun0")?, addr)
+    }
+
+    /// Run the stack on top of `nic` instead of tun0, with `addr` as the local address.
+    pub fn with_device<D: PacketDevice + 'static>(nic: D, addr: Ipv4Addr) -> io::Result<Self> {
         let ih: InterfaceHandle = Arc::default();
 
         // spwan a thread to process the nic packet
@@ -270,14 +803,165 @@ impl Interface {
         Ok(Interface{
             ih: Some(ih),
             jh: Some(jh),
+            addr,
+            addr6: None,
         })
     }
 
+    /// An interface without a packet thread, for a driver that feeds it packets and ticks itself.
+    fn unthreaded(addr: Ipv4Addr) -> Self {
+        Interface {
+            ih: Some(Arc::default()),
+            jh: None,
+            addr,
+            addr6: None,
+        }
+    }
+
+    /// Use `addr` as the local address of connections opened to IPv6 peers.
+    pub fn set_ipv6_addr(&mut self, addr: Ipv6Addr) {
+        self.addr6 = Some(addr);
+    }
+
+    /// the address to open a connection to `remote` from, of the same IP version
+    fn local_addr(&self, remote: IpAddr) -> io::Result<IpAddr> {
+        local_addr(self.addr, self.addr6, remote)
+    }
+
+    /// Open a connection to `remote`, blocking until the three-way handshake completes.
+    pub fn connect(&mut self, remote: impl Into<SocketAddr>) -> io::Result<TcpStream> {
+        self.connect_with_congestion_control(remote, NewReno::boxed)
+    }
+
+    /// Like `connect`, but the connection uses the congestion control made by `congestion`
+    /// instead of NewReno.
+    pub fn connect_with_congestion_control(
+        &mut self,
+        remote: impl Into<SocketAddr>,
+        congestion: CongestionFactory,
+    ) -> io::Result<TcpStream> {
+        let stream = self.start_connect(remote.into(), congestion, Instant::now())?;
+        let (quad, ih) = (stream.quad, &stream.ih);
+        let mut cm = ih.manager.lock().unwrap();
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            let c = cm.connections.get(&quad).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "stream was terminated unexpectedly",)
+            })?;
+            if let Some(e) = c.error() {
+                // refused or reset during the handshake, nobody holds this connection
+                cm.connections.remove(&quad);
+                return Err(e);
+            }
+            if c.is_synchronized() {
+                drop(cm);
+                return Ok(stream);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                cm.connections.remove(&quad);
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection timed out",
+                ));
+            }
+            cm = ih.pending_var.wait_timeout(cm, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Start an active open to `remote` without waiting for the handshake.
+    fn start_connect(
+        &mut self,
+        remote: SocketAddr,
+        congestion: CongestionFactory,
+        now: Instant,
+    ) -> io::Result<TcpStream> {
+        let local = self.local_addr(remote.ip())?;
+        let ih = self.ih.as_mut().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        let quad = cm.ephemeral_quad(local, remote)?;
+        let config = tcp::Config {
+            recv_buffer: cm.recv_buffer,
+            send_buffer: cm.send_buffer,
+            congestion,
+            sack: cm.sack,
+        };
+        let iss = cm.iss.generate(quad.dst, quad.src, now);
+        let c = tcp::Connection::connect(quad.dst, quad.src, iss, config, now);
+        cm.connections.insert(quad, c);
+        Ok(TcpStream {
+            quad,
+            ih: ih.clone(),
+        })
+    }
+
+    /// Set the receive buffer size of connections opened or accepted from now on.
+    ///
+    /// This bounds how much data the peer may send before the user reads it.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.recv_buffer = size;
+    }
+
+    /// Set the send buffer size of connections opened or accepted from now on.
+    ///
+    /// This bounds how much written data waits to be acked, and with it how much can be
+    /// in flight.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.send_buffer = size;
+    }
+
+    /// Whether connections opened or accepted from now on offer selective
+    /// acknowledgements (RFC 2018), on by default. They are only used if the peer
+    /// offers them too.
+    pub fn set_sack(&mut self, enabled: bool) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.sack = enabled;
+    }
+
+    /// Whether to fall back to SYN cookies when a listener's SYN queue is full, off by
+    /// default. Connections set up with a cookie use neither window scaling, SACK nor
+    /// timestamps, but a flood of SYNs can't keep anyone else from connecting.
+    pub fn set_syn_cookies(&mut self, enabled: bool) {
+        self.set_syn_cookies_at(enabled, Instant::now())
+    }
+
+    /// `set_syn_cookies`, with the cookies' time slots counted from `now`
+    fn set_syn_cookies_at(&mut self, enabled: bool, now: Instant) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.syn_cookies = enabled.then(|| tcp::cookie::SynCookies::new(tcp::random_key(), now));
+    }
+
+    /// Give every new connection `iss` as its initial sequence number, instead of one
+    /// picked as RFC 6528 says; only for deterministic tests.
+    pub(crate) fn pin_iss(&mut self, iss: Option<u32>) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.iss.pin(iss);
+    }
+
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_with_backlog(port, DEFAULT_BACKLOG)
+    }
+
+    /// `bind`, but with at most `backlog` connections in the handshake and at most
+    /// `backlog` waiting to be accepted; further SYNs are dropped until there is room.
+    pub fn bind_with_backlog(&mut self, port: u16, backlog: usize) -> io::Result<TcpListener> {
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
+        match cm.listeners.entry(port) {
             Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
+                v.insert(Listener {
+                    syn_queue: VecDeque::new(),
+                    pending: VecDeque::new(),
+                    // like listen(2), a backlog of 0 still lets a connection in
+                    backlog: std::cmp::max(backlog, 1),
+                    congestion: NewReno::boxed,
+                    nonblocking: false,
+                    accept_waker: None,
+                });
             }
             Entry::Occupied(_) => {
                 return Err(io::Error::new(
@@ -297,10 +981,15 @@ impl Interface {
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
+        // don't panic again if a panic poisoned the lock
+        if let Ok(mut cm) = self.ih.as_mut().unwrap().manager.lock() {
+            cm.terminate = true;
+        }
 
         drop(self.ih.take());
-        self.jh.take().expect("double free").join().unwrap().unwrap()
+        if let Some(jh) = self.jh.take() {
+            jh.join().unwrap().unwrap()
+        }
     }
 }
 pub struct TcpListener {
@@ -310,37 +999,238 @@ pub struct TcpListener {
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
-        let mut cm = self.ih.manager.lock().unwrap();
-        let pending = cm
-            .pending
+        // don't panic again if a panic poisoned the lock
+        let Ok(mut cm) = self.ih.manager.lock() else {
+            return;
+        };
+        let listener = cm
+            .listeners
             .remove(&self.port)
             .expect("port closed while listener still active");
 
-        for quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!();
+        // nobody is going to accept what is queued
+        for quad in listener.syn_queue.iter().chain(&listener.pending) {
+            if let Some(c) = cm.connections.get_mut(quad) {
+                c.reset();
+                // forgotten once the RST went out
+                c.detached = true;
+            }
         }
-
     }
 }
 
 impl TcpListener {
+    /// Use the congestion control made by `congestion` for connections accepted from now on.
+    pub fn set_congestion_control(&mut self, congestion: CongestionFactory) {
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .congestion = congestion;
+    }
+
+    /// Make `accept` fail with `WouldBlock` instead of waiting for a connection.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .nonblocking = nonblocking;
+        Ok(())
+    }
+
     pub fn accept(&mut self) -> io::Result<TcpStream> {
         let mut cm = self.ih.manager.lock().unwrap();
+        let nonblocking = cm.listeners[&self.port].nonblocking;
         loop {
-            if let Some(quad) = cm
-                .pending
-                .get_mut(&self.port)
-                .expect("port closed while listener still active")
-                .pop_front()
-            {
-                return Ok(TcpStream {
-                    quad,
-                    ih: self.ih.clone(),
-                });
+            match self.accept_nonblocking(&mut cm) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !nonblocking => {}
+                result => return result,
             }
 
             cm = self.ih.pending_var.wait(cm).unwrap();
         }
-    }    
+    }
+
+    /// one attempt at `accept` that returns `WouldBlock` if no connection is pending
+    fn accept_nonblocking(&self, cm: &mut ConnectionManager) -> io::Result<TcpStream> {
+        if let Some(quad) = cm
+            .listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .pending
+            .pop_front()
+        {
+            return Ok(TcpStream {
+                quad,
+                ih: self.ih.clone(),
+            });
+        }
+
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no pending connection"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Shutdown;
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn pair() -> (Interface, Interface) {
+        let (a, b) = ChannelDevice::pair();
+        (
+            Interface::with_device(a, A).unwrap(),
+            Interface::with_device(b, B).unwrap(),
+        )
+    }
+
+    fn read_to_eof(stream: &mut TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn connect_and_echo() {
+        let (mut a, mut b) = pair();
+        let mut listener = b.bind(8000).unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let mut buf = [0; 64];
+            let n = stream.read(&mut buf).unwrap();
+            stream.write_all(&buf[..n]).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            assert!(read_to_eof(&mut stream).is_empty());
+        });
+
+        let mut stream = a.connect((B, 8000)).unwrap();
+        stream.write_all(b"hello").unwrap();
+        assert_eq!(read_to_eof(&mut stream), b"hello");
+        stream.shutdown(Shutdown::Write).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn write_blocks_until_the_send_queue_drains() {
+        let (mut a, mut b) = pair();
+        let mut listener = b.bind(8000).unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            read_to_eof(&mut stream)
+        });
+
+        // many times the send queue, so write has to wait for ACKs over and over
+        let data: Vec<u8> = (0..20 * SENDQUEUE_SIZE as u32).map(|i| i as u8).collect();
+        let mut stream = a.connect((B, 8000)).unwrap();
+        assert_eq!(io::copy(&mut &data[..], &mut stream).unwrap(), data.len() as u64);
+        stream.flush().unwrap();
+        {
+            let cm = stream.ih.manager.lock().unwrap();
+            assert!(cm.connections[&stream.quad].unacked.is_empty());
+        }
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server.join().unwrap(), data);
+    }
+
+    #[test]
+    fn timeouts_and_nonblocking_mode() {
+        let (mut a, mut b) = pair();
+        let mut listener = b.bind(8000).unwrap();
+        let mut stream = a.connect((B, 8000)).unwrap();
+        // the server never reads
+        let _server = listener.accept().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+
+        let mut buf = [0; 64];
+        let timeout = Duration::from_millis(100);
+        let err = stream.set_read_timeout(Some(Duration::ZERO)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        let start = Instant::now();
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+
+        // fills the peer's window and our send queue, and then has to wait
+        stream.set_write_timeout(Some(timeout)).unwrap();
+        let data = vec![0; 4 * SENDQUEUE_SIZE];
+        assert_eq!(stream.write_all(&data).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        stream.set_nonblocking(true).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(stream.write(&data).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn udp_echo() {
+        let (mut a, mut b) = pair();
+        let server = UdpSocket::bind(&mut b, 5353).unwrap();
+        let client = UdpSocket::bind(&mut a, 0).unwrap();
+        assert!(EPHEMERAL_PORTS.contains(&client.local_addr().unwrap().port()));
+        let echo = thread::spawn(move || {
+            let mut buf = [0; 64];
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&buf[..n], from).unwrap();
+        });
+
+        client.connect((B, 5353)).unwrap();
+        client.send(b"hello").unwrap();
+        // a datagram larger than the buffer is cut short
+        let mut buf = [0; 4];
+        assert_eq!(client.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+        echo.join().unwrap();
+
+        let timeout = Duration::from_millis(100);
+        client.set_read_timeout(Some(timeout)).unwrap();
+        let start = Instant::now();
+        assert_eq!(client.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+        let err = client.send(&[0; 1500]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn connect_to_closed_port_is_refused() {
+        let (mut a, _b) = pair();
+        let err = a.connect((B, 8000)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn peer_closes_first() {
+        let (mut a, mut b) = pair();
+        let mut listener = b.bind(8000).unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            read_to_eof(&mut stream)
+        });
+
+        let mut stream = a.connect((B, 8000)).unwrap();
+        assert!(read_to_eof(&mut stream).is_empty());
+        // CLOSE-WAIT: we can still send
+        stream.write_all(b"bye").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server.join().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let (mut a, mut b) = pair();
+        let mut listener = b.bind(8000).unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            read_to_eof(&mut stream)
+        });
+
+        let mut stream = a.connect((B, 8000)).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert!(read_to_eof(&mut stream).is_empty());
+        assert!(server.join().unwrap().is_empty());
+    }
 }
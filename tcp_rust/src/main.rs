@@ -11,7 +11,7 @@ fn main() -> io::Result<()>{
     while let Ok(mut stream) = listener.accept() {
         eprintln!("got connection");
         thread::spawn(move || {
-            stream.write(b"hello from rust-tcp!\n").unwrap();
+            stream.write_all(b"hello from rust-tcp!\n").unwrap();
             stream.shutdown(std::net::Shutdown::Write).unwrap();
             loop {
                 let mut buf = [0; 512];
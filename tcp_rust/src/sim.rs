@@ -0,0 +1,1434 @@
+//! Deterministic network simulation.
+//!
+//! Two interfaces are connected by a simulated link and driven from a single thread
+//! with a virtual clock, so every run with the same seed sends, drops and delivers
+//! exactly the same segments at exactly the same (virtual) times.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use tcp_rust::sim::{Host, LinkConfig, Simulation};
+//!
+//! let mut sim = Simulation::new(42, LinkConfig { loss: 0.1, ..LinkConfig::default() });
+//! let mut listener = sim.bind(Host::B, 80).unwrap();
+//! let _client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+//! let accepted = sim.run_until(Duration::from_secs(60), |sim| sim.accept(&mut listener).is_ok());
+//! println!("{} {:?}", accepted, sim.trace());
+//! ```
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::device::PacketDevice;
+use crate::{CongestionFactory, Interface, NewReno, TcpListener, TcpStream, UdpSocket};
+
+/// virtual time that passes with every `Simulation::step`
+pub const STEP: Duration = crate::TICK;
+
+/// the two ends of the simulated link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Host {
+    A,
+    B,
+}
+
+impl Host {
+    pub fn addr(self) -> Ipv4Addr {
+        match self {
+            Host::A => Ipv4Addr::new(10, 0, 0, 1),
+            Host::B => Ipv4Addr::new(10, 0, 0, 2),
+        }
+    }
+
+    /// the host's IPv6 address, from the unique local range fd00::/8
+    pub fn addr6(self) -> Ipv6Addr {
+        match self {
+            Host::A => Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            Host::B => Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Faults the link injects, every probability is in `0.0..=1.0`.
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    /// one-way propagation delay
+    pub delay: Duration,
+    /// extra delay, uniformly distributed in `0..jitter`
+    pub jitter: Duration,
+    pub loss: f64,
+    pub duplicate: f64,
+    /// chance that a segment is held back long enough for later ones to overtake it
+    pub reorder: f64,
+    /// chance that a single bit of the packet is flipped
+    pub corrupt: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            delay: Duration::from_millis(5),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+        }
+    }
+}
+
+/// what the link did with a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    Delivered,
+    Dropped,
+    Duplicated,
+    Reordered,
+    Corrupted,
+}
+
+/// one packet put on the link, in the order they were sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// virtual time since the start of the simulation
+    pub at: Duration,
+    pub from: Host,
+    pub len: usize,
+    pub fate: Fate,
+}
+
+/// SplitMix64, small and good enough to roll the dice for the link
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        // 53 random bits make a uniform f64 in 0..1
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+}
+
+/// Collects what the stack sends until the simulation puts it on the link.
+#[derive(Default)]
+struct SimDevice {
+    outbox: Vec<Vec<u8>>,
+}
+
+impl PacketDevice for SimDevice {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbox.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn recv(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the simulation delivers packets itself",
+        ))
+    }
+
+    fn poll_recv(&mut self, _timeout: Duration) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+struct SimHost {
+    iface: Interface,
+    nic: SimDevice,
+}
+
+struct InFlight {
+    at: Instant,
+    /// tie breaker, so packets due at the same time arrive in the order they were sent
+    id: u64,
+    to: Host,
+    packet: Vec<u8>,
+}
+
+pub struct Simulation {
+    start: Instant,
+    now: Instant,
+    rng: Rng,
+    link: LinkConfig,
+    hosts: [SimHost; 2],
+    in_flight: Vec<InFlight>,
+    next_id: u64,
+    trace: Vec<Event>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, link: LinkConfig) -> Self {
+        // the sequence numbers are picked from the seed as well, without touching the
+        // dice the link rolls
+        let mut isn = Rng(!seed);
+        let mut host = |h: Host| {
+            let mut iface = Interface::unthreaded(h.addr());
+            iface.set_ipv6_addr(h.addr6());
+            iface.pin_iss(Some(isn.next_u64() as u32));
+            SimHost {
+                iface,
+                nic: SimDevice::default(),
+            }
+        };
+        let start = Instant::now();
+        Simulation {
+            start,
+            now: start,
+            rng: Rng(seed),
+            link,
+            hosts: [host(Host::A), host(Host::B)],
+            in_flight: Vec::new(),
+            next_id: 0,
+            trace: Vec::new(),
+        }
+    }
+
+    /// virtual time since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    /// every packet put on the link so far
+    pub fn trace(&self) -> &[Event] {
+        &self.trace
+    }
+
+    /// `Interface::set_recv_buffer_size` of `host`
+    pub fn set_recv_buffer_size(&mut self, host: Host, size: usize) {
+        self.hosts[host.index()].iface.set_recv_buffer_size(size)
+    }
+
+    /// `Interface::set_send_buffer_size` of `host`
+    pub fn set_send_buffer_size(&mut self, host: Host, size: usize) {
+        self.hosts[host.index()].iface.set_send_buffer_size(size)
+    }
+
+    /// Give every connection `host` opens or accepts from now on `iss` as its initial
+    /// sequence number.
+    pub fn pin_iss(&mut self, host: Host, iss: u32) {
+        self.hosts[host.index()].iface.pin_iss(Some(iss))
+    }
+
+    /// `Interface::set_sack` of `host`
+    pub fn set_sack(&mut self, host: Host, enabled: bool) {
+        self.hosts[host.index()].iface.set_sack(enabled)
+    }
+
+    pub fn bind(&mut self, host: Host, port: u16) -> io::Result<TcpListener> {
+        self.hosts[host.index()].iface.bind(port)
+    }
+
+    /// `UdpSocket::bind` on `host`, nonblocking, as nothing else would make progress while it waits
+    pub fn bind_udp(&mut self, host: Host, port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(&mut self.hosts[host.index()].iface, port)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    /// `Interface::set_syn_cookies` of `host`, on the virtual clock
+    pub fn set_syn_cookies(&mut self, host: Host, enabled: bool) {
+        let now = self.now;
+        self.hosts[host.index()].iface.set_syn_cookies_at(enabled, now)
+    }
+
+    pub fn bind_with_backlog(&mut self, host: Host, port: u16, backlog: usize) -> io::Result<TcpListener> {
+        self.hosts[host.index()].iface.bind_with_backlog(port, backlog)
+    }
+
+    /// Start an active open, the handshake runs as the simulation steps.
+    pub fn connect(&mut self, host: Host, remote: impl Into<SocketAddr>) -> io::Result<TcpStream> {
+        self.connect_with_congestion_control(host, remote, NewReno::boxed)
+    }
+
+    /// `connect` with the given congestion control instead of NewReno
+    pub fn connect_with_congestion_control(
+        &mut self,
+        host: Host,
+        remote: impl Into<SocketAddr>,
+        congestion: CongestionFactory,
+    ) -> io::Result<TcpStream> {
+        let now = self.now;
+        self.hosts[host.index()].iface.start_connect(remote.into(), congestion, now)
+    }
+
+    /// `TcpListener::accept`, but `WouldBlock` instead of waiting
+    pub fn accept(&self, listener: &mut TcpListener) -> io::Result<TcpStream> {
+        let mut cm = listener.ih.manager.lock().unwrap();
+        listener.accept_nonblocking(&mut cm)
+    }
+
+    /// `Read::read`, but `WouldBlock` instead of waiting
+    pub fn read(&self, stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cm = stream.ih.manager.lock().unwrap();
+        TcpStream::read_nonblocking(&mut cm, stream.quad, buf)
+    }
+
+    /// `Write::write`, but `WouldBlock` instead of waiting
+    pub fn write(&self, stream: &mut TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let mut cm = stream.ih.manager.lock().unwrap();
+        TcpStream::write_nonblocking(&mut cm, stream.quad, buf)
+    }
+
+    /// Advance the virtual clock by `STEP`: deliver every packet that is due,
+    /// run the timers of both hosts, and put whatever they sent on the link.
+    pub fn step(&mut self) {
+        self.now += STEP;
+
+        self.in_flight.sort_by_key(|p| (p.at, p.id));
+        let due = self.in_flight.partition_point(|p| p.at <= self.now);
+        for p in self.in_flight.drain(..due).collect::<Vec<_>>() {
+            let host = &mut self.hosts[p.to.index()];
+            let ih = host.iface.ih.as_ref().unwrap();
+            crate::on_datagram(ih, &mut host.nic, &p.packet, self.now)
+                .expect("simulated nic never fails");
+        }
+
+        for host in &mut self.hosts {
+            let ih = host.iface.ih.as_ref().unwrap();
+            crate::on_tick(ih, &mut host.nic, self.now).expect("simulated nic never fails");
+        }
+
+        for from in [Host::A, Host::B] {
+            let sent = std::mem::take(&mut self.hosts[from.index()].nic.outbox);
+            for packet in sent {
+                self.transmit(from, packet);
+            }
+        }
+    }
+
+    /// Step until `done` returns true or `limit` of virtual time has passed.
+    ///
+    /// Returns whether `done` was satisfied.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        let end = self.now + limit;
+        while self.now < end {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+
+    fn transmit(&mut self, from: Host, mut packet: Vec<u8>) {
+        let to = match from {
+            Host::A => Host::B,
+            Host::B => Host::A,
+        };
+        let link = self.link.clone();
+        let mut fate = Fate::Delivered;
+
+        if self.rng.chance(link.loss) {
+            fate = Fate::Dropped;
+        } else {
+            let copies = if self.rng.chance(link.duplicate) {
+                fate = Fate::Duplicated;
+                2
+            } else {
+                1
+            };
+            if self.rng.chance(link.corrupt) {
+                fate = Fate::Corrupted;
+                let bit = self.rng.below(packet.len() as u64 * 8) as usize;
+                packet[bit / 8] ^= 1 << (bit % 8);
+            }
+            for _ in 0..copies {
+                let jitter = self.rng.below(link.jitter.as_micros() as u64);
+                let mut delay = link.delay + Duration::from_micros(jitter);
+                if self.rng.chance(link.reorder) {
+                    fate = Fate::Reordered;
+                    delay += 3 * link.delay + link.jitter;
+                }
+                self.in_flight.push(InFlight {
+                    at: self.now + delay,
+                    id: self.next_id,
+                    to,
+                    packet: packet.clone(),
+                });
+                self.next_id += 1;
+            }
+        }
+
+        self.trace.push(Event {
+            at: self.elapsed(),
+            from,
+            len: packet.len(),
+            fate,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Keepalive;
+    use std::net::Shutdown;
+
+    /// A sends `data` to B and both close; returns what B received.
+    fn transfer(sim: &mut Simulation, data: &[u8], limit: Duration) -> Option<Vec<u8>> {
+        let mut listener = sim.bind(Host::B, 80).unwrap();
+        let mut client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+        let mut server = None;
+        let mut written = 0;
+        let mut received = Vec::new();
+        let (mut client_eof, mut server_eof) = (false, false);
+        let mut buf = [0u8; 512];
+
+        let done = sim.run_until(limit, |sim| {
+            if server.is_none() {
+                server = sim.accept(&mut listener).ok();
+            }
+            if written < data.len() {
+                if let Ok(n) = sim.write(&mut client, &data[written..]) {
+                    written += n;
+                    if written == data.len() {
+                        client.shutdown(Shutdown::Write).unwrap();
+                    }
+                }
+            }
+            if let Some(server) = server.as_mut() {
+                while !server_eof {
+                    match sim.read(server, &mut buf) {
+                        Ok(0) => {
+                            server_eof = true;
+                            server.shutdown(Shutdown::Write).unwrap();
+                        }
+                        Ok(n) => received.extend_from_slice(&buf[..n]),
+                        Err(_) => break,
+                    }
+                }
+            }
+            if !client_eof {
+                client_eof = matches!(sim.read(&mut client, &mut buf), Ok(0));
+            }
+            client_eof && server_eof
+        });
+        done.then_some(received)
+    }
+
+    /// a connection from A to B, once the handshake is done
+    fn connected(sim: &mut Simulation) -> (TcpStream, TcpStream) {
+        let mut listener = sim.bind(Host::B, 80).unwrap();
+        let client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+        let mut server = None;
+        assert!(sim.run_until(Duration::from_secs(1), |sim| {
+            server = server.take().or_else(|| sim.accept(&mut listener).ok());
+            server.is_some()
+        }));
+        (client, server.unwrap())
+    }
+
+    #[test]
+    fn transfer_over_perfect_link() {
+        let mut sim = Simulation::new(1, LinkConfig::default());
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let received = transfer(&mut sim, &data, Duration::from_secs(10)).unwrap();
+        assert_eq!(received, data);
+        assert!(sim.trace().iter().all(|e| e.fate == Fate::Delivered));
+    }
+
+    #[test]
+    fn transfer_over_reordering_link() {
+        let link = LinkConfig {
+            jitter: Duration::from_millis(20),
+            loss: 0.05,
+            duplicate: 0.05,
+            reorder: 0.2,
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(3, link);
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let received = transfer(&mut sim, &data, Duration::from_secs(3000)).unwrap();
+        assert_eq!(received, data);
+        assert!(sim.trace().iter().any(|e| e.fate == Fate::Reordered));
+    }
+
+    #[test]
+    fn receiver_that_does_not_read_stalls_the_sender() {
+        let mut sim = Simulation::new(5, LinkConfig::default());
+        sim.set_recv_buffer_size(Host::B, 1500);
+        let (mut client, mut server) = connected(&mut sim);
+        let data: Vec<u8> = (0..6000u32).map(|i| (i % 253) as u8).collect();
+        let mut written = 0;
+
+        // B doesn't read for a while, so its buffer fills up and the window closes
+        let buffered = |server: &TcpStream| {
+            let cm = server.ih.manager.lock().unwrap();
+            cm.connections[&server.quad].incoming.len()
+        };
+        sim.run_until(Duration::from_secs(10), |sim| {
+            if let Ok(n) = sim.write(&mut client, &data[written..]) {
+                written += n;
+            }
+            assert!(buffered(&server) <= 1500);
+            false
+        });
+        assert_eq!(buffered(&server), 1500);
+        {
+            // A holds on to the rest until B makes room
+            let cm = client.ih.manager.lock().unwrap();
+            assert_eq!(cm.connections[&client.quad].unacked.len(), written - 1500);
+        }
+
+        // once B starts reading, window updates let the rest through
+        let mut received = Vec::new();
+        let mut buf = [0u8; 512];
+        let done = sim.run_until(Duration::from_secs(600), |sim| {
+            if written < data.len() {
+                if let Ok(n) = sim.write(&mut client, &data[written..]) {
+                    written += n;
+                }
+            }
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert!(buffered(&server) <= 1500);
+            received.len() == data.len()
+        });
+        assert!(done);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn zero_window_is_probed_until_it_opens() {
+        let mut sim = Simulation::new(6, LinkConfig::default());
+        sim.set_recv_buffer_size(Host::B, 1000);
+        let (mut client, mut server) = connected(&mut sim);
+        let data: Vec<u8> = (0..1800u32).map(|i| (i % 241) as u8).collect();
+        let mut written = 0;
+        sim.run_until(Duration::from_secs(30), |sim| {
+            if let Ok(n) = sim.write(&mut client, &data[written..]) {
+                written += n;
+            }
+            false
+        });
+        assert_eq!(written, data.len());
+
+        // the window is closed: A only sends the odd probe, backing off between them
+        let from_a = |sim: &Simulation, after: Duration| {
+            sim.trace().iter().filter(|e| e.from == Host::A && e.at >= after).count()
+        };
+        let probes = from_a(&sim, Duration::from_secs(5));
+        assert!((2..=5).contains(&probes), "{} probes", probes);
+
+        // B reads, but the window update it sends is lost
+        let mut received = vec![0u8; 1000];
+        assert_eq!(sim.read(&mut server, &mut received).unwrap(), 1000);
+        sim.step();
+        assert_eq!(sim.in_flight.len(), 1);
+        sim.in_flight.clear();
+
+        // a probe picks up the open window and the rest of the data follows
+        let mut buf = [0u8; 512];
+        let done = sim.run_until(Duration::from_secs(60), |sim| {
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            received.len() == data.len()
+        });
+        assert!(done);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn lost_segments_are_retransmitted_after_the_rto() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(50),
+            loss: 0.2,
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(4, link);
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 239) as u8).collect();
+        let received = transfer(&mut sim, &data, Duration::from_secs(120)).unwrap();
+        assert_eq!(received, data);
+        assert!(sim.trace().iter().any(|e| e.fate == Fate::Dropped));
+    }
+
+    #[test]
+    fn transfer_across_sequence_number_wraparound() {
+        let link = LinkConfig {
+            loss: 0.05,
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(18, link);
+        sim.pin_iss(Host::A, u32::MAX - 10_000);
+        sim.pin_iss(Host::B, u32::MAX);
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 247) as u8).collect();
+        let received = transfer(&mut sim, &data, Duration::from_secs(120)).unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn slow_start_sends_the_initial_window_first() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(100),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(9, link);
+        let mut listener = sim.bind(Host::B, 80).unwrap();
+        let mut client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+        let data = vec![7u8; 20_000];
+        let mut server = None;
+        sim.run_until(Duration::from_millis(250), |sim| {
+            let _ = sim.write(&mut client, &data);
+            if server.is_none() {
+                server = sim.accept(&mut listener).ok();
+            }
+            false
+        });
+        // after the handshake, one round trip is not over yet: only the initial window of
+        // 4380 bytes is out, that's three segments of 1448 bytes and a small one
+        let segments = |sim: &Simulation| {
+            sim.trace().iter().filter(|e| e.from == Host::A && e.len == 1500).count()
+        };
+        assert_eq!(segments(&sim), 3);
+
+        // the ACK of the first two segments grows the window by both of them, so four
+        // more go out; the third one is acked only once the delayed ACK timer fires
+        sim.run_until(Duration::from_millis(200), |_| false);
+        assert_eq!(segments(&sim), 3 + 4);
+    }
+
+    #[test]
+    fn fast_retransmit_repairs_a_lost_segment() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(20),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(10, link);
+        let (mut client, mut server) = connected(&mut sim);
+        let data: Vec<u8> = (0..30_000u32).map(|i| (i % 233) as u8).collect();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut dropped = false;
+
+        let done = sim.run_until(Duration::from_millis(900), |sim| {
+            if written < data.len() {
+                if let Ok(n) = sim.write(&mut client, &data[written..]) {
+                    written += n;
+                }
+            }
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            // lose one data segment of a window of several, once
+            let big = sim.in_flight.iter().filter(|p| p.to == Host::B && p.packet.len() > 100).count();
+            if !dropped && big >= 6 {
+                let at = sim.in_flight.iter().position(|p| p.to == Host::B && p.packet.len() > 100).unwrap();
+                sim.in_flight.remove(at);
+                dropped = true;
+            }
+            received.len() == data.len()
+        });
+        assert!(dropped);
+        // well before the retransmission timer could have fired
+        assert!(done, "only {} bytes after {:?}", received.len(), sim.elapsed());
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn timeout_resends_a_lost_window_within_cwnd() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(10),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(32, link);
+        let (mut client, mut server) = connected(&mut sim);
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 233) as u8).collect();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut dropped = None;
+
+        let done = sim.run_until(Duration::from_secs(10), |sim| {
+            if written < data.len() {
+                if let Ok(n) = sim.write(&mut client, &data[written..]) {
+                    written += n;
+                }
+            }
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            // a blackout that loses a window of many segments, and every ACK until then,
+            // so only the retransmission timer can tell
+            let big = sim.in_flight.iter().filter(|p| p.to == Host::B && p.packet.len() > 100).count();
+            if dropped.is_none() && big >= 20 {
+                dropped = Some(sim.elapsed());
+            }
+            if dropped.is_some_and(|at| sim.elapsed() < at + Duration::from_millis(500)) {
+                sim.in_flight.clear();
+            }
+            received.len() == data.len()
+        });
+        assert_eq!(received, data);
+        assert!(done);
+        // the timer fires after a second, then slow start takes a few round trips, not one
+        // (delayed) ACK per lost segment
+        let recovery = sim.elapsed() - dropped.unwrap();
+        assert!(recovery < Duration::from_millis(1500), "took {:?}", recovery);
+    }
+
+    /// A writes a byte every step for a second; returns the data segments it sent
+    fn small_writes(nodelay: bool) -> usize {
+        let link = LinkConfig {
+            delay: Duration::from_millis(20),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(11, link);
+        let (mut client, mut server) = connected(&mut sim);
+        client.set_nodelay(nodelay).unwrap();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 512];
+        sim.run_until(Duration::from_secs(2), |sim| {
+            if written < 100 {
+                written += sim.write(&mut client, &[written as u8]).unwrap();
+            }
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            false
+        });
+        assert_eq!(received, (0..100).collect::<Vec<u8>>());
+        // the headers take 52 bytes with timestamps, the SYN's options take more
+        sim.trace().iter().filter(|e| e.from == Host::A && e.len > 52).count() - 1
+    }
+
+    #[test]
+    fn nagle_coalesces_small_writes_unless_nodelay() {
+        // one small segment per delayed ACK
+        assert!(small_writes(false) < 10, "{}", small_writes(false));
+        assert_eq!(small_writes(true), 100);
+    }
+
+    #[test]
+    fn every_second_segment_is_acked() {
+        let mut sim = Simulation::new(12, LinkConfig::default());
+        let data = vec![3u8; 100_000];
+        let received = transfer(&mut sim, &data, Duration::from_secs(10)).unwrap();
+        assert_eq!(received, data);
+        let segments = sim.trace().iter().filter(|e| e.from == Host::A && e.len == 1500).count();
+        let acks = sim.trace().iter().filter(|e| e.from == Host::B).count();
+        // give or take the handshake, the FIN and a few window updates
+        assert!(acks <= segments / 2 + 5, "{} ACKs for {} segments", acks, segments);
+    }
+
+
+    /// how many bytes A gets across to B in `duration` when it always has more to send
+    fn goodput(sim: &mut Simulation, congestion: CongestionFactory, duration: Duration) -> usize {
+        let mut listener = sim.bind(Host::B, 80).unwrap();
+        let mut client = sim.connect_with_congestion_control(Host::A, (Host::B.addr(), 80), congestion).unwrap();
+        let data = [0x55u8; 4096];
+        let mut server = None;
+        let mut received = 0;
+        let mut buf = [0u8; 4096];
+
+        sim.run_until(duration, |sim| {
+            while sim.write(&mut client, &data).is_ok() {}
+            if server.is_none() {
+                server = sim.accept(&mut listener).ok();
+            }
+            if let Some(server) = server.as_mut() {
+                while let Ok(n) = sim.read(server, &mut buf) {
+                    received += n;
+                }
+            }
+            false
+        });
+        received
+    }
+
+    #[test]
+    fn cubic_outperforms_newreno_on_a_long_lossy_link() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(200),
+            loss: 0.001,
+            ..LinkConfig::default()
+        };
+        let duration = Duration::from_secs(60);
+        let newreno = goodput(&mut Simulation::new(7, link.clone()), NewReno::boxed, duration);
+        let cubic = goodput(&mut Simulation::new(7, link), crate::Cubic::boxed, duration);
+        // CUBIC gets back to the window it lost at much sooner
+        assert!(cubic > newreno * 3 / 2, "newreno {} cubic {}", newreno, cubic);
+    }
+
+    #[test]
+    fn window_scaling_fills_a_long_fat_link() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(3, link);
+        sim.set_send_buffer_size(Host::A, 1 << 20);
+        sim.set_recv_buffer_size(Host::B, 1 << 20);
+        let duration = Duration::from_secs(2);
+        let received = goodput(&mut sim, NewReno::boxed, duration);
+        // an unscaled window lets through 64 KiB per round trip of 100ms at most
+        let unscaled = u16::MAX as usize * 10 * duration.as_secs() as usize;
+        assert!(received > 4 * unscaled, "{} bytes", received);
+    }
+
+    #[test]
+    fn paws_drops_a_segment_with_an_old_timestamp() {
+        let mut sim = Simulation::new(5, LinkConfig::default());
+        let (mut client, mut server) = connected(&mut sim);
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let (first, second) = data.split_at(20_000);
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut old = None;
+
+        let mut send = |sim: &mut Simulation, part: &[u8], old: &mut Option<Vec<u8>>| {
+            let mut written = 0;
+            let goal = received.len() + part.len();
+            let done = sim.run_until(Duration::from_secs(10), |sim| {
+                if written < part.len() {
+                    written += sim.write(&mut client, &part[written..]).unwrap_or(0);
+                }
+                while let Ok(n) = sim.read(&mut server, &mut buf) {
+                    received.extend_from_slice(&buf[..n]);
+                }
+                if old.is_none() {
+                    *old = sim.in_flight.iter().find(|p| p.to == Host::B && p.packet.len() > 100).map(|p| p.packet.clone());
+                }
+                received.len() == goal
+            });
+            assert!(done);
+        };
+        send(&mut sim, first, &mut old);
+
+        // the first data segment comes back, with a sequence number that wrapped around
+        // into the window: only its timestamp gives it away
+        let packet = old.clone().unwrap();
+        let (ip, rest) = etherparse::Ipv4Header::from_slice(&packet).unwrap();
+        let (mut tcp, payload) = etherparse::TcpHeader::from_slice(rest).unwrap();
+        let payload: Vec<u8> = payload.iter().map(|b| !b).collect();
+        tcp.sequence_number = tcp.sequence_number.wrapping_add(first.len() as u32);
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, &payload).unwrap();
+        let mut forged = ip.to_bytes().to_vec();
+        forged.extend_from_slice(&tcp.to_bytes());
+        forged.extend_from_slice(&payload);
+        sim.in_flight.push(InFlight {
+            at: sim.now,
+            id: sim.next_id,
+            to: Host::B,
+            packet: forged,
+        });
+        sim.next_id += 1;
+
+        send(&mut sim, second, &mut old);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn transfer_over_ipv6() {
+        let mut sim = Simulation::new(23, LinkConfig::default());
+        let mut listener = sim.bind(Host::B, 80).unwrap();
+        let mut client = sim.connect(Host::A, (Host::B.addr6(), 80)).unwrap();
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let mut server = None;
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        assert!(sim.run_until(Duration::from_secs(10), |sim| {
+            if written < data.len() {
+                written += sim.write(&mut client, &data[written..]).unwrap_or(0);
+            }
+            if server.is_none() {
+                server = sim.accept(&mut listener).ok();
+            }
+            if let Some(server) = &mut server {
+                while let Ok(n) = sim.read(server, &mut buf) {
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+            received.len() == data.len()
+        }));
+        assert_eq!(received, data);
+        {
+            let cm = sim.hosts[Host::B.index()].iface.ih.as_ref().unwrap().manager.lock().unwrap();
+            assert!(cm.connections.keys().all(|q| q.src.ip() == Host::A.addr6()));
+        }
+        // the MSS leaves room for the larger header
+        let largest = sim.trace().iter().map(|e| e.len).max().unwrap();
+        assert_eq!(largest, 1500);
+    }
+
+    #[test]
+    fn ipv6_extension_headers_are_skipped() {
+        let mut sim = Simulation::new(24, LinkConfig::default());
+        let _listener = sim.bind(Host::B, 80).unwrap();
+
+        // a SYN behind hop-by-hop and destination options, both just padding
+        let padding = etherparse::Ipv6RawExtHeader::new_raw(etherparse::IpNumber::TCP, &[1, 4, 0, 0, 0, 0]).unwrap();
+        let ip = etherparse::IpHeaders::Ipv6(
+            etherparse::Ipv6Header {
+                hop_limit: 64,
+                source: Host::A.addr6().octets(),
+                destination: Host::B.addr6().octets(),
+                ..Default::default()
+            },
+            etherparse::Ipv6Extensions {
+                hop_by_hop_options: Some(padding.clone()),
+                destination_options: Some(padding),
+                ..Default::default()
+            },
+        );
+        let mut syn = Vec::new();
+        etherparse::PacketBuilder::ip(ip)
+            .tcp(50000, 80, 1000, 1024)
+            .syn()
+            .write(&mut syn, &[])
+            .unwrap();
+        sim.in_flight.push(InFlight {
+            at: sim.now,
+            id: sim.next_id,
+            to: Host::B,
+            packet: syn,
+        });
+        sim.next_id += 1;
+
+        sim.step();
+        // answered with a SYN-ACK
+        assert_eq!(connections(&sim, Host::B), 1);
+        assert!(sim.trace().iter().any(|e| e.from == Host::B));
+    }
+
+    fn inject(sim: &mut Simulation, to: Host, packet: Vec<u8>) {
+        sim.in_flight.push(InFlight {
+            at: sim.now,
+            id: sim.next_id,
+            to,
+            packet,
+        });
+        sim.next_id += 1;
+    }
+
+    /// an ICMPv4 message from `from` to the other host
+    fn icmp(from: Host, icmp_type: etherparse::Icmpv4Type, payload: &[u8]) -> Vec<u8> {
+        let to = if from == Host::A { Host::B } else { Host::A };
+        let mut packet = Vec::new();
+        etherparse::PacketBuilder::ipv4(from.addr().octets(), to.addr().octets(), 64)
+            .icmpv4(icmp_type)
+            .write(&mut packet, payload)
+            .unwrap();
+        packet
+    }
+
+    /// take the first data segment on its way to `to` off the link
+    fn take_data_segment(sim: &mut Simulation, to: Host) -> Vec<u8> {
+        let i = sim
+            .in_flight
+            .iter()
+            .position(|p| {
+                let ip = etherparse::Ipv4Slice::from_slice(&p.packet).unwrap();
+                let tcp = etherparse::TcpSlice::from_slice(ip.payload().payload);
+                p.to == to && tcp.is_ok_and(|tcp| !tcp.payload().is_empty())
+            })
+            .unwrap();
+        sim.in_flight.remove(i).packet
+    }
+
+    #[test]
+    fn pings_are_answered_and_unknown_protocols_unreachable() {
+        let mut sim = Simulation::new(25, LinkConfig::default());
+        let echo = etherparse::IcmpEchoHeader { id: 7, seq: 1 };
+        inject(&mut sim, Host::B, icmp(Host::A, etherparse::Icmpv4Type::EchoRequest(echo), b"ping"));
+        sim.step();
+        let reply = sim.in_flight.pop().unwrap();
+        assert_eq!(reply.to, Host::A);
+        let ip = etherparse::Ipv4Slice::from_slice(&reply.packet).unwrap();
+        let reply = etherparse::Icmpv4Slice::from_slice(ip.payload().payload).unwrap();
+        assert_eq!(reply.icmp_type(), etherparse::Icmpv4Type::EchoReply(echo));
+        assert_eq!(reply.payload(), b"ping");
+
+        // but not those to everyone
+        let mut broadcast = Vec::new();
+        etherparse::PacketBuilder::ipv4(Host::A.addr().octets(), [255, 255, 255, 255], 64)
+            .icmpv4(etherparse::Icmpv4Type::EchoRequest(echo))
+            .write(&mut broadcast, b"ping")
+            .unwrap();
+        inject(&mut sim, Host::B, broadcast);
+        sim.step();
+        assert!(sim.in_flight.is_empty());
+
+        // SCTP, which we don't speak
+        let mut sctp = Vec::new();
+        etherparse::Ipv4Header::new(4, 64, etherparse::IpNumber(132), Host::A.addr().octets(), Host::B.addr().octets())
+            .unwrap()
+            .write(&mut sctp)
+            .unwrap();
+        sctp.extend_from_slice(&[0, 80, 0, 80]);
+        inject(&mut sim, Host::B, sctp.clone());
+        sim.step();
+        let reply = sim.in_flight.pop().unwrap();
+        let ip = etherparse::Ipv4Slice::from_slice(&reply.packet).unwrap();
+        let reply = etherparse::Icmpv4Slice::from_slice(ip.payload().payload).unwrap();
+        assert_eq!(
+            reply.icmp_type(),
+            etherparse::Icmpv4Type::DestinationUnreachable(etherparse::icmpv4::DestUnreachableHeader::Protocol)
+        );
+        // quoting the datagram
+        assert_eq!(reply.payload(), &sctp[..]);
+    }
+
+    #[test]
+    fn udp_datagrams_reach_bound_ports_only() {
+        let mut sim = Simulation::new(28, LinkConfig::default());
+        let client = sim.bind_udp(Host::A, 0).unwrap();
+        let server = sim.bind_udp(Host::B, 53).unwrap();
+        client.send_to(b"over v4", (Host::B.addr(), 53)).unwrap();
+        client.send_to(b"over v6", (Host::B.addr6(), 53)).unwrap();
+        let mut received = Vec::new();
+        assert!(sim.run_until(Duration::from_secs(1), |_| {
+            let mut buf = [0u8; 64];
+            while let Ok((n, from)) = server.recv_from(&mut buf) {
+                received.push((buf[..n].to_vec(), from));
+            }
+            received.len() == 2
+        }));
+        let port = client.local_addr().unwrap().port();
+        assert_eq!(received[0], (b"over v4".to_vec(), SocketAddr::from((Host::A.addr(), port))));
+        assert_eq!(received[1], (b"over v6".to_vec(), SocketAddr::from((Host::A.addr6(), port))));
+        client.connect((Host::B.addr6(), 53)).unwrap();
+        assert_eq!(client.local_addr().unwrap(), SocketAddr::from((Host::A.addr6(), port)));
+
+        let mut datagram = Vec::new();
+        etherparse::PacketBuilder::ipv4(Host::A.addr().octets(), Host::B.addr().octets(), 64)
+            .udp(port, 54)
+            .write(&mut datagram, b"anyone?")
+            .unwrap();
+        inject(&mut sim, Host::B, datagram.clone());
+        sim.step();
+        let reply = sim.in_flight.pop().unwrap();
+        let ip = etherparse::Ipv4Slice::from_slice(&reply.packet).unwrap();
+        let reply = etherparse::Icmpv4Slice::from_slice(ip.payload().payload).unwrap();
+        assert_eq!(
+            reply.icmp_type(),
+            etherparse::Icmpv4Type::DestinationUnreachable(etherparse::icmpv4::DestUnreachableHeader::Port)
+        );
+        assert_eq!(reply.payload(), &datagram[..28]);
+    }
+
+    #[test]
+    fn port_unreachable_aborts_the_connection() {
+        let mut sim = Simulation::new(26, LinkConfig::default());
+        let (mut client, _server) = connected(&mut sim);
+        sim.write(&mut client, b"hello").unwrap();
+        sim.step();
+        let segment = take_data_segment(&mut sim, Host::B);
+
+        let unreachable = etherparse::icmpv4::DestUnreachableHeader::Port;
+        let error = icmp(Host::B, etherparse::Icmpv4Type::DestinationUnreachable(unreachable.clone()), &segment[..28]);
+        // one that quotes a segment that isn't in flight is ignored
+        let mut stale = segment.clone();
+        stale[24..28].copy_from_slice(&[0, 0, 0, 0]);
+        let stale = icmp(Host::B, etherparse::Icmpv4Type::DestinationUnreachable(unreachable), &stale[..28]);
+        inject(&mut sim, Host::A, stale);
+        sim.step();
+        let mut buf = [0u8; 16];
+        assert_eq!(sim.read(&mut client, &mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        inject(&mut sim, Host::A, error);
+        sim.step();
+        assert_eq!(sim.read(&mut client, &mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn fragmentation_needed_lowers_the_mss() {
+        let mut sim = Simulation::new(27, LinkConfig::default());
+        let (mut client, mut server) = connected(&mut sim);
+        // a single full-sized segment, so no duplicate ACKs can repair it either
+        let data: Vec<u8> = (0..1448u32).map(|i| (i % 239) as u8).collect();
+        let mut written = sim.write(&mut client, &data).unwrap();
+        sim.step();
+        let segment = take_data_segment(&mut sim, Host::B);
+        assert_eq!(segment.len(), 1500);
+
+        let too_big = etherparse::icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu: 1000 };
+        let error = icmp(Host::B, etherparse::Icmpv4Type::DestinationUnreachable(too_big), &segment[..28]);
+        inject(&mut sim, Host::A, error);
+        let since = sim.elapsed();
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        // resent right away, not once the retransmission timer expires
+        assert!(sim.run_until(Duration::from_millis(500), |sim| {
+            if written < data.len() {
+                written += sim.write(&mut client, &data[written..]).unwrap_or(0);
+            }
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            received.len() == data.len()
+        }));
+        assert_eq!(received, data);
+        let sent = sim.trace().iter().filter(|e| e.from == Host::A && e.at > since);
+        assert!(sent.map(|e| e.len).max().unwrap() <= 1000);
+    }
+
+    #[test]
+    fn fragmentation_needed_resends_within_the_window() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(29, link);
+        let (mut client, mut server) = connected(&mut sim);
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 239) as u8).collect();
+        let mut written = sim.write(&mut client, &data).unwrap();
+        sim.step();
+        // the initial window, all of it too large for the path
+        let segments: Vec<_> = std::iter::from_fn(|| {
+            sim.in_flight.iter().any(|p| p.to == Host::B).then(|| take_data_segment(&mut sim, Host::B))
+        })
+        .collect();
+        let window: usize = segments.iter().map(|s| s.len() - 52).sum();
+        let too_big = etherparse::icmpv4::DestUnreachableHeader::FragmentationNeeded { next_hop_mtu: 576 };
+        let error = icmp(Host::B, etherparse::Icmpv4Type::DestinationUnreachable(too_big), &segments[0][..28]);
+        inject(&mut sim, Host::A, error);
+
+        // payload bytes on their way to B
+        let on_the_link = |sim: &Simulation| -> Vec<usize> {
+            sim.in_flight.iter().filter(|p| p.to == Host::B).map(|p| p.packet.len() - 52).collect()
+        };
+        sim.step();
+        // one segment to try the new size, the rest waits for its ACK
+        assert_eq!(on_the_link(&sim).len(), 1);
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        // in flight at once until what was dropped is repaired
+        let mut most = 0;
+        assert!(sim.run_until(Duration::from_secs(2), |sim| {
+            if written < data.len() {
+                written += sim.write(&mut client, &data[written..]).unwrap_or(0);
+            }
+            while let Ok(n) = sim.read(&mut server, &mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            if received.len() < window {
+                most = std::cmp::max(most, on_the_link(sim).iter().sum());
+            }
+            received.len() == data.len()
+        }));
+        assert_eq!(received, data);
+        // within the window, which grew by the segment the first ACK acked
+        let mss = 576 - 52;
+        assert!(most <= window + mss, "{} bytes in flight for a window of {}", most, window);
+    }
+
+    /// a copy of `segment` without ACK or options, with SYN if `syn`, carrying `payload`
+    fn without_ack(segment: &[u8], syn: bool, payload: &[u8]) -> Vec<u8> {
+        let ip = etherparse::Ipv4Slice::from_slice(segment).unwrap();
+        let tcp = etherparse::TcpSlice::from_slice(ip.payload().payload).unwrap();
+        let builder = etherparse::PacketBuilder::ipv4(ip.header().source(), ip.header().destination(), 64)
+            .tcp(tcp.source_port(), tcp.destination_port(), tcp.sequence_number(), tcp.window_size());
+        let builder = if syn { builder.syn() } else { builder };
+        let mut packet = Vec::new();
+        builder.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    #[test]
+    fn segments_without_ack_are_dropped() {
+        let mut sim = Simulation::new(30, LinkConfig::default());
+        let (mut client, mut server) = connected(&mut sim);
+        sim.write(&mut client, b"hello").unwrap();
+        sim.step();
+        let segment = take_data_segment(&mut sim, Host::B);
+        // neither data nor an empty segment moves RCV.NXT without an ACK
+        inject(&mut sim, Host::B, without_ack(&segment, false, b"bogus"));
+        inject(&mut sim, Host::B, without_ack(&segment, false, b""));
+        inject(&mut sim, Host::B, segment);
+        sim.step();
+        let mut buf = [0u8; 16];
+        let n = sim.read(&mut server, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+    }
+
+    #[test]
+    fn syn_in_syn_rcvd_is_answered_with_the_syn_ack() {
+        let mut sim = Simulation::new(31, LinkConfig::default());
+        let mut listener = sim.bind(Host::B, 80).unwrap();
+        let _client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+        sim.step();
+        let syn = sim.in_flight.iter().find(|p| p.to == Host::B).unwrap().packet.clone();
+        let syn_ack = |sim: &mut Simulation| sim.in_flight.iter().position(|p| p.to == Host::A);
+        assert!(sim.run_until(Duration::from_millis(100), |sim| syn_ack(sim).is_some()));
+        let i = syn_ack(&mut sim).unwrap();
+        sim.in_flight.remove(i);
+
+        // the SYN again, with data this time
+        inject(&mut sim, Host::B, without_ack(&syn, true, b"early"));
+        sim.step();
+        assert!(syn_ack(&mut sim).is_some());
+        // well before either side's retransmission timer
+        assert!(sim.run_until(Duration::from_millis(500), |sim| sim.accept(&mut listener).is_ok()));
+    }
+
+    #[test]
+    fn sack_repairs_several_losses_in_one_round_trip() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+        // the transfer ends with the window that loses segments, so it takes as long as the repair
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 241) as u8).collect();
+        let elapsed = |sack| {
+            let mut sim = Simulation::new(4, link.clone());
+            sim.set_sack(Host::A, sack);
+            let mut listener = sim.bind(Host::B, 80).unwrap();
+            let mut client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+            let mut written = 0;
+            let mut server = None;
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4096];
+            let mut dropped = false;
+
+            let done = sim.run_until(Duration::from_secs(10), |sim| {
+                if written < data.len() {
+                    written += sim.write(&mut client, &data[written..]).unwrap_or(0);
+                }
+                if server.is_none() {
+                    server = sim.accept(&mut listener).ok();
+                }
+                if let Some(server) = server.as_mut() {
+                    while let Ok(n) = sim.read(server, &mut buf) {
+                        received.extend_from_slice(&buf[..n]);
+                    }
+                }
+                // lose three segments of a large window, with others in between
+                let big: Vec<usize> = (0..sim.in_flight.len())
+                    .filter(|&i| sim.in_flight[i].to == Host::B && sim.in_flight[i].packet.len() > 100)
+                    .collect();
+                if !dropped && big.len() >= 12 {
+                    for &i in [big[5], big[3], big[1]].iter() {
+                        sim.in_flight.remove(i);
+                    }
+                    dropped = true;
+                }
+                received.len() == data.len()
+            });
+            assert!(done);
+            assert!(dropped);
+            assert_eq!(received, data);
+            sim.elapsed()
+        };
+        let (with, without) = (elapsed(true), elapsed(false));
+        // NewReno finds one hole per round trip of 100ms, SACK all of them at once
+        assert!(with + Duration::from_millis(150) < without, "{:?} with SACK, {:?} without", with, without);
+    }
+
+    /// connections `host` keeps track of, closed or not
+    fn connections(sim: &Simulation, host: Host) -> usize {
+        let ih = sim.hosts[host.index()].iface.ih.as_ref().unwrap();
+        let cm = ih.manager.lock().unwrap();
+        cm.connections.len()
+    }
+
+    #[test]
+    fn time_wait_holds_the_quad_for_two_msl() {
+        let mut sim = Simulation::new(13, LinkConfig::default());
+        let data = vec![5u8; 3000];
+        // both streams are dropped once it's done
+        transfer(&mut sim, &data, Duration::from_secs(10)).unwrap();
+        sim.run_until(Duration::from_secs(1), |_| false);
+        // B closed last, A closed first and waits
+        assert_eq!(connections(&sim, Host::B), 0);
+        assert_eq!(connections(&sim, Host::A), 1);
+
+        sim.run_until(Duration::from_secs(58), |_| false);
+        assert_eq!(connections(&sim, Host::A), 1);
+        sim.run_until(Duration::from_secs(2), |_| false);
+        assert_eq!(connections(&sim, Host::A), 0);
+    }
+
+    #[test]
+    fn dropping_a_stream_closes_it() {
+        let mut sim = Simulation::new(14, LinkConfig::default());
+        let (client, mut server) = connected(&mut sim);
+
+        drop(client);
+        let mut buf = [0u8; 16];
+        assert!(sim.run_until(Duration::from_secs(1), |sim| matches!(sim.read(&mut server, &mut buf), Ok(0))));
+
+        // B never closes, so A gives up waiting for its FIN after a while
+        sim.run_until(Duration::from_secs(61), |_| false);
+        assert_eq!(connections(&sim, Host::A), 0);
+        assert_eq!(connections(&sim, Host::B), 1);
+
+        // and resets B's FIN once it comes
+        drop(server);
+        sim.run_until(Duration::from_secs(1), |_| false);
+        assert_eq!(connections(&sim, Host::B), 0);
+    }
+
+    #[test]
+    fn syns_beyond_the_backlog_are_dropped() {
+        let mut sim = Simulation::new(15, LinkConfig::default());
+        let mut listener = sim.bind_with_backlog(Host::B, 80, 2).unwrap();
+        let _clients: Vec<_> = (0..3)
+            .map(|_| sim.connect(Host::A, (Host::B.addr(), 80)).unwrap())
+            .collect();
+        sim.run_until(Duration::from_millis(500), |_| false);
+        // the third SYN found the accept queue full
+        assert_eq!(connections(&sim, Host::B), 2);
+
+        assert!(sim.accept(&mut listener).is_ok());
+        assert!(sim.accept(&mut listener).is_ok());
+        assert!(sim.accept(&mut listener).is_err());
+        // there is room again when the SYN is retransmitted
+        assert!(sim.run_until(Duration::from_secs(2), |sim| sim.accept(&mut listener).is_ok()));
+    }
+
+    #[test]
+    fn syn_cookies_get_past_a_full_syn_queue() {
+        let link = LinkConfig {
+            delay: Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+        let mut sim = Simulation::new(17, link);
+        sim.set_syn_cookies(Host::B, true);
+        let mut listener = sim.bind_with_backlog(Host::B, 80, 2).unwrap();
+        let mut clients = vec![
+            sim.connect(Host::A, (Host::B.addr(), 80)).unwrap(),
+            sim.connect(Host::A, (Host::B.addr(), 80)).unwrap(),
+        ];
+        sim.step();
+        // arrives while the other two are still in the handshake
+        clients.push(sim.connect(Host::A, (Host::B.addr(), 80)).unwrap());
+        sim.run_until(Duration::from_millis(70), |_| false);
+        assert_eq!(connections(&sim, Host::B), 2);
+        for (i, client) in clients.iter_mut().enumerate() {
+            sim.write(client, &[i as u8]).unwrap();
+        }
+
+        let mut servers = Vec::new();
+        let mut buf = [0u8; 16];
+        let mut received = Vec::new();
+        // a dropped SYN would only be retransmitted after a second
+        assert!(sim.run_until(Duration::from_millis(500), |sim| {
+            while let Ok(server) = sim.accept(&mut listener) {
+                servers.push(server);
+            }
+            for server in &mut servers {
+                if let Ok(n) = sim.read(server, &mut buf) {
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+            received.len() == 3
+        }));
+        received.sort();
+        assert_eq!(received, [0, 1, 2]);
+    }
+
+    #[test]
+    fn dropping_a_listener_resets_its_queued_connections() {
+        let mut sim = Simulation::new(16, LinkConfig::default());
+        let listener = sim.bind(Host::B, 80).unwrap();
+        let mut client = sim.connect(Host::A, (Host::B.addr(), 80)).unwrap();
+        sim.run_until(Duration::from_millis(100), |_| false);
+        assert_eq!(connections(&sim, Host::B), 1);
+
+        drop(listener);
+        let mut buf = [0u8; 16];
+        assert!(sim.run_until(Duration::from_secs(1), |sim| {
+            matches!(sim.read(&mut client, &mut buf), Err(e) if e.kind() == io::ErrorKind::ConnectionReset)
+        }));
+        assert_eq!(connections(&sim, Host::B), 0);
+        // and the port is free again
+        assert!(sim.bind(Host::B, 80).is_ok());
+    }
+
+    #[test]
+    fn keepalive_finds_a_vanished_peer() {
+        let mut sim = Simulation::new(19, LinkConfig::default());
+        let (mut client, _server) = connected(&mut sim);
+        client
+            .set_keepalive(Some(Keepalive {
+                idle: Duration::from_secs(10),
+                interval: Duration::from_secs(1),
+                count: 3,
+            }))
+            .unwrap();
+
+        // the peer answers the probes, so an idle connection stays up
+        let mut buf = [0u8; 16];
+        sim.run_until(Duration::from_secs(30), |_| false);
+        let probes = sim.trace().iter().filter(|e| e.from == Host::A).count();
+        // the SYN, the ACK and a probe every 10 seconds
+        assert!(probes >= 4, "{} segments", probes);
+        assert_eq!(sim.read(&mut client, &mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        // until it's gone: at most idle plus count intervals later, at least count intervals
+        sim.link.loss = 1.0;
+        let start = sim.now;
+        assert!(sim.run_until(Duration::from_secs(14), |sim| {
+            sim.read(&mut client, &mut buf).map_err(|e| e.kind()) == Err(io::ErrorKind::TimedOut)
+        }));
+        assert!(sim.now - start >= Duration::from_secs(3), "{:?}", sim.now - start);
+    }
+
+    #[test]
+    fn unreachable_peer_times_out() {
+        let mut sim = Simulation::new(2, LinkConfig::default());
+        let (mut client, _server) = connected(&mut sim);
+
+        // the link goes down for good while A has data in flight
+        sim.link.loss = 1.0;
+        sim.write(&mut client, b"anyone there?").unwrap();
+        let mut buf = [0u8; 16];
+        let mut error = None;
+        sim.run_until(Duration::from_secs(30 * 60), |sim| {
+            error = sim.read(&mut client, &mut buf).err().filter(|e| e.kind() != io::ErrorKind::WouldBlock);
+            error.is_some()
+        });
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::TimedOut);
+        // 15 retransmissions with backoff take 1 + 2 + ... + 32 + 10 * 60 seconds
+        let elapsed = sim.elapsed().as_secs();
+        assert!((600..=700).contains(&elapsed), "gave up after {}s", elapsed);
+    }
+
+    #[test]
+    fn same_seed_same_trace() {
+        let link = LinkConfig {
+            jitter: Duration::from_millis(3),
+            loss: 0.2,
+            duplicate: 0.1,
+            corrupt: 0.1,
+            ..LinkConfig::default()
+        };
+        let run = |seed| {
+            let mut sim = Simulation::new(seed, link.clone());
+            let received = transfer(&mut sim, b"hello", Duration::from_secs(600));
+            (received, sim.trace().to_vec())
+        };
+
+        let (received, trace) = run(7);
+        assert_eq!(received.as_deref(), Some(&b"hello"[..]));
+        assert!(trace.iter().any(|e| e.fate != Fate::Delivered));
+        assert_eq!(run(7), (received, trace.clone()));
+        assert_ne!(run(8).1, trace);
+    }
+}
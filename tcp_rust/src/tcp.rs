@@ -1,37 +1,275 @@
 use std::collections::{BTreeMap, VecDeque};
 use std::{io, time};
+use std::task::Waker;
+use std::time::Instant;
 use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use bitflags::bitflags;
-use etherparse::IpNumber;
+use etherparse::{IpHeaders, IpNumber, IpSlice};
+use crate::device::PacketDevice;
+use crate::icmp::IcmpError;
+
+pub(crate) mod congestion;
+pub(crate) mod cookie;
+pub(crate) mod iss;
+mod options;
+mod reassembly;
+mod scoreboard;
+mod siphash;
+
+use congestion::{CongestionControl, CongestionFactory};
+use cookie::SynCookies;
+use etherparse::TcpOptionElement;
+use options::{Options, MAX_WINDOW_SCALE, TIMESTAMP_LEN};
+use reassembly::Reassembly;
+use scoreboard::Scoreboard;
+pub(crate) use siphash::random_key;
+
+/// the MSS every host must accept (RFC 1122 S4.2.2.6), used until we know better
+const DEFAULT_MSS: usize = 536;
+/// the same over IPv6, what fits in its minimum MTU of 1280 (RFC 8200 S5)
+const DEFAULT_MSS_V6: usize = 1220;
+/// the largest IP packet we send, and the one we ask the peer for: Ethernet's
+pub(crate) const MTU: usize = 1500;
+/// a TCP header without options
+const TCP_HEADER_LEN: usize = 20;
+/// smaller path MTUs are ignored, they are most likely forged (RFC 5927 S7.2);
+/// every IPv4 host takes datagrams this large (RFC 791)
+const MIN_PATH_MTU: usize = 576;
+/// TS.Recent is too old for PAWS when it hasn't been updated this long (RFC 7323 S5.5)
+const PAWS_IDLE: time::Duration = time::Duration::from_secs(24 * 24 * 60 * 60);
+/// bounds of the retransmission timeout (RFC 6298 S2.4, S2.5), also its value before the first sample
+const MIN_RTO: time::Duration = time::Duration::from_secs(1);
+const MAX_RTO: time::Duration = time::Duration::from_secs(60);
+/// how often a segment is retransmitted before the connection is given up
+const MAX_RETRIES: u32 = 15;
+/// the same for our SYN, an unanswered handshake gives up much sooner
+const SYN_RETRIES: u32 = 6;
+/// the persist timer starts at the RTO and doubles with every unanswered probe, up to this
+const PERSIST_MAX: time::Duration = time::Duration::from_secs(60);
+/// maximum segment lifetime, TIME-WAIT lasts twice that; RFC 793 says 2 minutes, but
+/// like most stacks we don't wait that long
+const MSL: time::Duration = time::Duration::from_secs(30);
+/// how long an ACK may be held back hoping for data to ride on (RFC 1122 S4.2.3.2 allows 500ms)
+const DELAYED_ACK: time::Duration = time::Duration::from_millis(200);
 
 enum State {
 //    Listen,
+    SynSent,
     SynRcvd,
     Estab,
-    TimeWait,
     FinWait1,
     FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
 }
 
 struct Timers {
+    /// when the segments in flight were sent, to take RTT samples from their ACKs;
+    /// retransmitted segments are left out, their ACK is ambiguous (Karn's algorithm)
     send_times: BTreeMap<u32, time::Instant>,
-    srtt: f64,
+    /// smoothed round-trip time (RFC 6298), None until the first sample
+    srtt: Option<time::Duration>,
+    /// round-trip time variation
+    rttvar: time::Duration,
+    /// retransmission timeout
+    rto: time::Duration,
+    /// when the retransmission timer expires, running while anything is unacked
+    retransmit: Option<time::Instant>,
+    /// how often the oldest unacked segment has been retransmitted
+    retries: u32,
+    /// when to probe a zero send window next, running only while the window is closed
+    persist: Option<time::Instant>,
+    /// probes sent since the window closed
+    probes: u32,
+    /// when a held back ACK has to go out at the latest
+    delayed_ack: Option<time::Instant>,
+    /// when a connection in TIME-WAIT, or a detached one stuck in FIN-WAIT-2, is closed
+    close: Option<time::Instant>,
+    /// when to send the next keepalive probe, running only while the connection is idle
+    keepalive: Option<time::Instant>,
+    /// keepalive probes sent since we last heard from the peer
+    keepalive_probes: u32,
+}
+
+impl Timers {
+    fn new() -> Self {
+        Timers {
+            send_times: Default::default(),
+            srtt: None,
+            rttvar: time::Duration::ZERO,
+            rto: MIN_RTO,
+            retransmit: None,
+            retries: 0,
+            persist: None,
+            probes: 0,
+            delayed_ack: None,
+            close: None,
+            keepalive: None,
+            keepalive_probes: 0,
+        }
+    }
+
+    /// Fold a round-trip time measurement into SRTT and RTTVAR and recompute the RTO (RFC 6298 S2).
+    fn sample(&mut self, r: time::Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(r);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + r / 8);
+            }
+        }
+        // the packet thread only looks at the timers every TICK, that's our clock granularity
+        let rto = self.srtt.unwrap() + std::cmp::max(crate::TICK, 4 * self.rttvar);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// the retransmission timer expired, double the RTO (RFC 6298 S5.5)
+    fn back_off(&mut self) {
+        self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+    }
+}
+
+/// Timestamps option state (RFC 7323 S3, S4, S5).
+struct Timestamps {
+    /// TSval 0, our timestamp clock ticks once a millisecond from here
+    epoch: Instant,
+    /// whether both ends put timestamps on every segment
+    enabled: bool,
+    /// TS.Recent, the TSval to echo
+    recent: u32,
+    /// when TS.Recent was last updated
+    recent_at: Instant,
+    /// Last.ACK.sent, RCV.NXT as of our last segment
+    last_ack_sent: u32,
+}
+
+impl Timestamps {
+    fn new(now: Instant) -> Self {
+        Timestamps {
+            epoch: now,
+            enabled: false,
+            recent: 0,
+            recent_at: now,
+            last_ack_sent: 0,
+        }
+    }
+
+    /// our TSval at `now`
+    fn clock(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.epoch).as_millis() as u32
+    }
+
+    /// the round trip time an ACK with this TSecr measures (RFC 7323 S4.1)
+    fn rtt(&self, tsecr: u32, now: Instant) -> time::Duration {
+        time::Duration::from_millis(self.clock(now).wrapping_sub(tsecr) as u64)
+    }
+}
+
+/// a loss recovery in progress (RFC 6582)
+enum Recovery {
+    /// entered on three duplicate ACKs, each further one inflates the window by `inflation`
+    Fast { inflation: usize },
+    /// the same with SACK (RFC 6675): what gets sent is decided by the scoreboard,
+    /// everything below `high_rxt` has been retransmitted already
+    Sack { high_rxt: u32 },
+    /// entered when the retransmission timer expired: everything in flight goes out again
+    /// as the ACKs open the window, everything below `high_rxt` has been resent already
+    Timeout { high_rxt: u32 },
+    /// entered when a router dropped our segments as too large for the path (RFC 1191):
+    /// they go out again in smaller ones as the ACKs come in, everything below `high_rxt`
+    /// has been resent already
+    PathMtu { high_rxt: u32 },
 }
 
 pub struct Connection {
     state: State,
     send: SendSeqBlock,
     recv: RecvSeqBlock,
-    ip: etherparse::Ipv4Header,
+    ip: IpHeaders,
     tcp: etherparse::TcpHeader,
     timers: Timers,
 
     pub(crate) incoming: VecDeque<u8>,
+    /// how many bytes `incoming` may hold, the window we advertise never goes beyond it
+    recv_buffer: usize,
+    /// in-window data that arrived ahead of RCV.NXT
+    reassembly: Reassembly,
+    /// bytes accepted since we last sent an ACK
+    ack_pending: usize,
     pub(crate) unacked: VecDeque<u8>,
+    /// how many bytes `unacked` may hold, `write` blocks beyond this
+    pub(crate) send_buffer: usize,
     pub(crate) closed: bool,
     closed_at: Option<u32>,
+    /// send small segments right away instead of coalescing them (Nagle off)
+    pub(crate) nodelay: bool,
+    /// where the last segment shorter than the MSS ended
+    small_sent: u32,
+    /// why the connection was aborted, reported to the user on every read/write
+    error: Option<io::ErrorKind>,
+    /// the user dropped its `TcpStream`, nobody reads or writes anymore
+    pub(crate) detached: bool,
+    /// tell the peer with an RST on the next tick that we aborted the connection
+    reset: bool,
+    /// probe the peer once the connection is idle, off unless the user asks for it
+    pub(crate) keepalive: Option<Keepalive>,
+    /// how long a blocking `read` waits for data before it fails with `TimedOut`
+    pub(crate) read_timeout: Option<time::Duration>,
+    /// how long a blocking `write` or `flush` waits for room or ACKs
+    pub(crate) write_timeout: Option<time::Duration>,
+    /// `read`, `write` and `flush` fail with `WouldBlock` instead of waiting
+    pub(crate) nonblocking: bool,
+    /// tasks of the async front end waiting for data to read, or for room to write
+    pub(crate) read_waker: Option<Waker>,
+    pub(crate) write_waker: Option<Waker>,
+
+    timestamps: Timestamps,
+    /// whether both ends use selective acknowledgements (RFC 2018)
+    sack: bool,
+    scoreboard: Scoreboard,
+
+    /// the most data we put in a segment: the peer's MSS less the options we send
+    mss: usize,
+    congestion: Box<dyn CongestionControl>,
+    /// makes `congestion` again once the handshake settled the MSS
+    make_congestion: CongestionFactory,
+    /// duplicate ACKs in a row (RFC 5681 S3.2)
+    dupacks: u32,
+    /// SND.NXT when the last loss was detected, until ACKs get beyond it
+    recover: Option<u32>,
+    recovery: Option<Recovery>,
+}
+
+
+/// When and how often to check whether the peer of an idle connection is still there
+/// (RFC 1122 S4.2.3.6).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// how long the connection has to be idle before the first probe
+    pub idle: time::Duration,
+    /// the time between unanswered probes
+    pub interval: time::Duration,
+    /// how many probes go unanswered before the connection is given up
+    pub count: u32,
 }
 
+/// What a new connection is set up with, taken from the interface and listener.
+#[derive(Clone, Copy)]
+pub(crate) struct Config {
+    pub(crate) recv_buffer: usize,
+    pub(crate) send_buffer: usize,
+    pub(crate) congestion: CongestionFactory,
+    /// whether to offer selective acknowledgements
+    pub(crate) sack: bool,
+}
 
 bitflags! {
     pub(crate) struct Available: u8 {
@@ -42,22 +280,165 @@ bitflags! {
 
 
 impl Connection {
+    /// whether the three-way handshake has completed (RFC 793 S3.4)
+    pub(crate) fn is_synchronized(&self) -> bool {
+        !matches!(self.state, State::SynSent | State::SynRcvd)
+    }
+
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: any state after rcvd FIN, so also CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
+        // any state after rcvd FIN
+        matches!(
+            self.state,
+            State::CloseWait | State::LastAck | State::Closing | State::TimeWait | State::Closed
+        )
+    }
+
+    /// whether the connection is gone for good, and can be forgotten once detached
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// the error the connection was aborted with, if any
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        self.error.map(io::Error::from)
+    }
+
+    /// Tear the connection down without the closing handshake, e.g. on a valid RST.
+    pub(crate) fn abort(&mut self, kind: io::ErrorKind) {
+        // all segment queues should be flushed (RFC 793 S3.9)
+        self.state = State::Closed;
+        self.error = Some(kind);
+        self.incoming.clear();
+        self.reassembly = Reassembly::default();
+        self.unacked.clear();
+        self.timers.send_times.clear();
+        self.timers.retransmit = None;
+        self.timers.persist = None;
+        self.timers.close = None;
+        self.scoreboard.clear();
+    }
+
+    /// ABORT on the user's behalf (RFC 793 S3.9): flush everything and reset the peer.
+    pub(crate) fn reset(&mut self) {
+        // in the other states the peer either never heard of us or is done already
+        let reset = matches!(
+            self.state,
+            State::SynRcvd | State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait
+        );
+        self.abort(io::ErrorKind::ConnectionAborted);
+        self.reset = reset;
+    }
+
+    /// Wait out 2MSL, so old duplicates of this connection die out before the quad can be
+    /// reused, and a lost ACK of the peer's FIN can still be repeated (RFC 793 S3.5).
+    fn time_wait(&mut self, now: Instant) {
+        self.state = State::TimeWait;
+        self.timers.retransmit = None;
+        self.timers.close = Some(now + 2 * MSL);
+    }
+
+    /// The window to advertise, based on how much of the receive buffer is free.
+    ///
+    /// The window only opens once it can grow by a full segment or half the buffer,
+    /// so we don't invite the sender to send tiny segments (RFC 1122 S4.2.3.3).
+    /// It never closes below RCV.WND either: the bytes the user hasn't read yet have all
+    /// been accepted inside the current window, so the right edge never moves left.
+    /// With window scaling, only multiples of the scale can be advertised.
+    fn receive_window(&self) -> u32 {
+        let free = self.recv_buffer.saturating_sub(self.incoming.len());
+        let current = self.recv.wnd as usize;
+        let threshold = std::cmp::min(self.recv_buffer / 2, DEFAULT_MSS);
+        if free >= current + threshold {
+            let largest = (u16::MAX as usize) << self.recv.wscale;
+            let granularity = (1usize << self.recv.wscale) - 1;
+            std::cmp::max(std::cmp::min(free, largest) & !granularity, current) as u32
         } else {
-            false
+            current as u32
+        }
+    }
+
+    /// the window of a segment other than a SYN, scaled to bytes (RFC 7323 S2.3)
+    fn send_window(&self, tcph: &etherparse::TcpHeaderSlice) -> u32 {
+        (tcph.window_size() as u32) << self.send.wscale.unwrap_or(0)
+    }
+
+    /// Settle the options of the handshake given the peer's SYN: each of them is only
+    /// used if both ends asked for it (RFC 7323 S1.3), and we never send segments
+    /// larger than the peer can take (RFC 1122 S4.2.2.6).
+    fn negotiate(&mut self, syn: &Options, now: Instant) {
+        self.send.wscale = syn.window_scale;
+        if syn.window_scale.is_none() {
+            self.recv.wscale = 0;
+        }
+        self.sack &= syn.sack_permitted;
+        self.timestamps.enabled = syn.timestamp.is_some();
+        if let Some((tsval, _)) = syn.timestamp {
+            self.timestamps.recent = tsval;
+            self.timestamps.recent_at = now;
         }
+
+        let mss = std::cmp::min(syn.mss.map_or(default_mss(&self.ip), usize::from), own_mss(&self.ip));
+        self.mss = std::cmp::max(mss.saturating_sub(self.options_len()), 1);
+        self.congestion = (self.make_congestion)(self.mss);
     }
 
+    /// the options on every segment after the handshake, the MSS doesn't account for
+    /// them, they take away from the data (RFC 6691)
+    fn options_len(&self) -> usize {
+        if self.timestamps.enabled {
+            TIMESTAMP_LEN
+        } else {
+            0
+        }
+    }
+
+    /// An ICMP error about the segment we sent with sequence number `seq` (RFC 5927): a
+    /// hard error aborts the connection, and if the segment was too large for the path,
+    /// the MSS drops to fit the path MTU (RFC 1191) and what is in flight goes out again
+    /// in segments that fit, as none of it made it.
+    pub(crate) fn on_icmp_error(&mut self,
+        nic: &mut dyn PacketDevice,
+        error: IcmpError,
+        seq: u32,
+        now: Instant) -> io::Result<()> {
+            // anyone can forge an ICMP message, but hardly guess what is in flight
+            if !Self::is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt) {
+                return Ok(());
+            }
+            match error {
+                IcmpError::Unreachable => self.abort(io::ErrorKind::ConnectionRefused),
+                IcmpError::FragmentationNeeded { mtu } => {
+                    let mtu = usize::from(mtu);
+                    if mtu < MIN_PATH_MTU {
+                        return Ok(());
+                    }
+                    let mss = mtu - self.ip.header_len() - TCP_HEADER_LEN - self.options_len();
+                    if mss < self.mss {
+                        // a recovery in progress resends with the new MSS already
+                        self.mss = mss;
+                        if self.is_synchronized() && self.recovery.is_none() && self.flight() > 0 {
+                            // like a timeout, but the network isn't congested: the segment at
+                            // SND.UNA goes out right away, the ACKs clock out the rest
+                            self.recover = Some(self.send.nxt);
+                            self.dupacks = 0;
+                            let high_rxt = self.retransmit(nic, now)?;
+                            self.recovery = Some(Recovery::PathMtu { high_rxt });
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+
      fn availability(&self) -> Available {
         let mut a = Available::empty();
         if self.is_rcv_closed() || !self.incoming.is_empty() {
             a |= Available::READ;
         }
-        // TODO: take into account self.state
-        // TODO: set Available::WRITE
+        if self.error.is_some() || self.unacked.len() < self.send_buffer {
+            // write won't block, and neither will flush if the queue is empty
+            a |= Available::WRITE;
+        }
         a
     }
 }
@@ -110,7 +491,7 @@ impl Connection {
 
 /// State of the Send Sequence Space (RFC 793 S3.2 F4)
 ///
-/// ```
+/// ```text
 ///            1         2          3          4
 ///       ----------|----------|----------|----------
 ///              SND.UNA    SND.NXT    SND.UNA
@@ -127,13 +508,16 @@ struct SendSeqBlock {
     /// send next
     nxt: u32,
     /// send window
-    wnd: u16,
+    wnd: u32,
+    /// how far the peer shifts its window field, None if it doesn't scale it
+    wscale: Option<u8>,
     /// send urgent pointer
+    #[allow(dead_code)]
     up: u16,
     /// segment sequence number used for last window update
-    wl1: usize,
+    wl1: u32,
     /// segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: u32,
     /// initial send sequence number
     iss: u32,
 }
@@ -147,7 +531,7 @@ struct SendSeqBlock {
 
 /// State of the Receive Sequence Space (RFC 793 S3.2 F5)
 ///
-/// ```
+/// ```text
 ///                1          2          3
 ///            ----------|----------|----------
 ///                   RCV.NXT    RCV.NXT
@@ -161,95 +545,261 @@ struct RecvSeqBlock {
     /// receive next
     nxt: u32,
     /// receive window
-    wnd: u16,
+    wnd: u32,
+    /// how far we shift our window field
+    wscale: u8,
     /// receive urgent pointer
+    #[allow(dead_code)]
     up: u16,
     /// initial receive sequence number
     irs: u32,
 }
 impl Connection {
-    pub fn accept(nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice,
+    pub fn accept(nic: &mut dyn PacketDevice,
+        ip: &IpSlice,
         tcph: etherparse::TcpHeaderSlice,
-        data: &[u8]) -> io::Result<Option<Self>> {
+        data: &[u8],
+        iss: u32,
+        config: Config,
+        now: Instant) -> io::Result<Option<Self>> {
             // process the SYN and send ACK/SYN
 //            let buf =[0u8;1500];
+            if tcph.rst() {
+                return Ok(None);
+            }
+            if tcph.ack() {
+                // nothing can be acked in LISTEN: <SEQ=SEG.ACK><CTL=RST>
+                send_rst(nic, ip, &tcph, data.len())?;
+                return Ok(None);
+            }
             if !tcph.syn() {
                 return Ok(None);
             }
 
-            let iss = 0; // actual iss need be some random value, here just use 0
-            let wnd = 1024;
-            let mut c = Connection {
-                state: State::SynRcvd,
-                send: SendSeqBlock {
-                    iss,
-                    una: iss,
-                    nxt: iss,
-                    wnd,
-                    up: 0,
-
-                    wl1: 0,
-                    wl2: 0,
-                },
-                recv: RecvSeqBlock {
-                    irs: tcph.sequence_number(),
-                    nxt: tcph.sequence_number() + 1,
-                    wnd: tcph.window_size(),
-                    up: 0,
-                },
-                tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
-                ip: etherparse::Ipv4Header::new(0, 64, IpNumber::TCP,
-                    [
-                        iph.destination()[0],
-                        iph.destination()[1],
-                        iph.destination()[2],
-                        iph.destination()[3],
-                    ],
-                    [
-                        iph.source()[0],
-                        iph.source()[1],
-                        iph.source()[2],
-                        iph.source()[3],
-                    ]).unwrap(),
-                timers: Timers {
-                    send_times: Default::default(),
-                    srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
-                },
-                incoming: Default::default(),
-                unacked: Default::default(),
-                closed: false,
-                closed_at: None,
-                
-            };
+            let mut c = Self::syn_rcvd(ip, &tcph, tcph.sequence_number(), iss, config, now);
+            c.negotiate(&Options::parse(&tcph), now);
+            c.tcp.syn = true;
+            c.tcp.ack = true;
+            c.write(nic, c.send.nxt, 0, now)?;
+            Ok(Some(c))
+        }
+
+    /// Answer a SYN with a SYN cookie as the ISS, without keeping any state.
+    ///
+    /// Only the MSS survives in the cookie, so we don't offer window scaling, SACK or
+    /// timestamps.
+    pub(crate) fn send_syn_cookie(nic: &mut dyn PacketDevice,
+        ip: &IpSlice,
+        tcph: etherparse::TcpHeaderSlice,
+        config: Config,
+        cookies: &SynCookies,
+        now: Instant) -> io::Result<()> {
+            let local = SocketAddr::new(ip.destination_addr(), tcph.destination_port());
+            let remote = SocketAddr::new(ip.source_addr(), tcph.source_port());
+            let (cookie, mss) = cookies.make(local, remote, Options::parse(&tcph).mss, now);
+            let mut c = Self::syn_rcvd(ip, &tcph, tcph.sequence_number(), cookie, config, now);
+            c.negotiate(&Options { mss: Some(mss), ..Default::default() }, now);
             c.tcp.syn = true;
             c.tcp.ack = true;
-            c.write(nic,c.send.nxt,0)?;
+            c.write(nic, c.send.nxt, 0, now)?;
+            Ok(())
+        }
+
+    /// The final ACK of a handshake we answered with a SYN cookie: if the cookie is
+    /// ours, the connection is created as if it had been in SYN-RECEIVED all along.
+    pub(crate) fn from_syn_cookie(nic: &mut dyn PacketDevice,
+        ip: &IpSlice,
+        tcph: etherparse::TcpHeaderSlice,
+        data: &[u8],
+        config: Config,
+        cookies: &SynCookies,
+        now: Instant) -> io::Result<Option<Self>> {
+            let local = SocketAddr::new(ip.destination_addr(), tcph.destination_port());
+            let remote = SocketAddr::new(ip.source_addr(), tcph.source_port());
+            let cookie = tcph.acknowledgment_number().wrapping_sub(1);
+            let Some(mss) = cookies.check(local, remote, cookie, now) else {
+                return Ok(None);
+            };
+            let irs = tcph.sequence_number().wrapping_sub(1);
+            let mut c = Self::syn_rcvd(ip, &tcph, irs, cookie, config, now);
+            c.negotiate(&Options { mss: Some(mss), ..Default::default() }, now);
+            // our SYN went out with the cookie
+            c.send.nxt = cookie.wrapping_add(1);
+            c.on_packet(nic, ip, tcph, data, now)?;
             Ok(Some(c))
         }
 
+    /// a connection in SYN-RECEIVED, answering a SYN with sequence number `irs`
+    fn syn_rcvd(ip: &IpSlice,
+        tcph: &etherparse::TcpHeaderSlice,
+        irs: u32,
+        iss: u32,
+        config: Config,
+        now: Instant) -> Self {
+        // the window of a SYN is never scaled
+        let wnd = std::cmp::min(config.recv_buffer, u16::MAX as usize) as u16;
+        let ip = ip_header(ip.destination_addr(), ip.source_addr());
+        let mss = default_mss(&ip);
+        Connection {
+            state: State::SynRcvd,
+            send: SendSeqBlock {
+                iss,
+                una: iss,
+                nxt: iss,
+                wnd: tcph.window_size() as u32,
+                wscale: None,
+                up: 0,
+
+                wl1: irs,
+                wl2: iss,
+            },
+            recv: RecvSeqBlock {
+                irs,
+                nxt: irs.wrapping_add(1),
+                wnd: wnd as u32,
+                wscale: window_scale(config.recv_buffer),
+                up: 0,
+            },
+            tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
+            ip,
+            timers: Timers::new(),
+            incoming: Default::default(),
+            recv_buffer: config.recv_buffer,
+            reassembly: Default::default(),
+            ack_pending: 0,
+            unacked: Default::default(),
+            send_buffer: config.send_buffer,
+            closed: false,
+            closed_at: None,
+            nodelay: false,
+            small_sent: iss,
+            error: None,
+            detached: false,
+            reset: false,
+            keepalive: None,
+            read_timeout: None,
+            write_timeout: None,
+            nonblocking: false,
+            read_waker: None,
+            write_waker: None,
+            timestamps: Timestamps::new(now),
+            sack: config.sack,
+            scoreboard: Default::default(),
+            mss,
+            congestion: (config.congestion)(mss),
+            make_congestion: config.congestion,
+            dupacks: 0,
+            recover: None,
+            recovery: None,
+        }
+    }
+
+    /// Active OPEN (RFC 793 S3.8): the connection starts out in SYN-SENT.
+    ///
+    /// The SYN itself is sent by the next `on_tick`, since only the packet thread owns the nic.
+    pub fn connect(local: SocketAddr, remote: SocketAddr, iss: u32, config: Config, now: Instant) -> Self {
+        // the window of a SYN is never scaled
+        let wnd = std::cmp::min(config.recv_buffer, u16::MAX as usize) as u16;
+        let ip = ip_header(local.ip(), remote.ip());
+        let mss = default_mss(&ip);
+        let mut c = Connection {
+            state: State::SynSent,
+            send: SendSeqBlock {
+                iss,
+                una: iss,
+                nxt: iss,
+                // learned from the peer's SYN
+                wnd: 0,
+                wscale: None,
+                up: 0,
+
+                wl1: 0,
+                wl2: 0,
+            },
+            // filled in once the peer's SYN arrives
+            recv: RecvSeqBlock {
+                irs: 0,
+                nxt: 0,
+                wnd: wnd as u32,
+                wscale: window_scale(config.recv_buffer),
+                up: 0,
+            },
+            tcp: etherparse::TcpHeader::new(local.port(), remote.port(), iss, wnd),
+            ip,
+            timers: Timers::new(),
+            incoming: Default::default(),
+            recv_buffer: config.recv_buffer,
+            reassembly: Default::default(),
+            ack_pending: 0,
+            unacked: Default::default(),
+            send_buffer: config.send_buffer,
+            closed: false,
+            closed_at: None,
+            nodelay: false,
+            small_sent: iss,
+            error: None,
+            detached: false,
+            reset: false,
+            keepalive: None,
+            read_timeout: None,
+            write_timeout: None,
+            nonblocking: false,
+            read_waker: None,
+            write_waker: None,
+            timestamps: Timestamps::new(now),
+            sack: config.sack,
+            scoreboard: Default::default(),
+            mss,
+            congestion: (config.congestion)(mss),
+            make_congestion: config.congestion,
+            dupacks: 0,
+            recover: None,
+            recovery: None,
+        };
+        c.tcp.syn = true;
+        c
+    }
+
     pub fn write(&mut self,
-        nic: &mut tun_tap::Iface,
+        nic: &mut dyn PacketDevice,
         seq: u32,
-        mut limit: usize) -> io::Result<usize> {
-            let mut buf = [0u8; 1500];
+        mut limit: usize,
+        now: Instant) -> io::Result<usize> {
+            let mut buf = [0u8; MTU];
             //setup sequence
             // self.tcp.sequence_number = self.send.nxt;
             self.tcp.sequence_number = seq;
             self.tcp.acknowledgment_number = self.recv.nxt;
+            self.recv.wnd = self.receive_window();
+            self.tcp.window_size = if self.tcp.syn {
+                std::cmp::min(self.recv.wnd, u16::MAX as u32) as u16
+            } else {
+                (self.recv.wnd >> self.recv.wscale) as u16
+            };
+            self.set_options(now);
+            self.timestamps.last_ack_sent = self.recv.nxt;
+            // whatever we send acknowledges everything up to RCV.NXT
+            self.ack_pending = 0;
+            self.timers.delayed_ack = None;
             //if !self.tcp.syn && !self.tcp.fin {
             //    self.tcp.psh = true;
             //}
 
             // TODO: return +1 for SYN/FIN
-            println!(
-                "write(ack: {}, seq: {}, limit: {}) syn {:?} fin {:?}",
-                self.recv.nxt - self.recv.irs, seq, limit,self.tcp.syn, self.tcp.fin,
-            );
 
-            let mut offset = seq.wrapping_sub(self.send.una) as usize;
             // we need to special-case the two "virtual" bytes SYN and FIN
-            println!("FIN close {:?}", self.closed_at);
+            let mut offset = if self.send.una == self.send.iss {
+                // our SYN isn't acked yet, so data starts just beyond it
+                seq.wrapping_sub(self.send.una).saturating_sub(1) as usize
+            } else {
+                seq.wrapping_sub(self.send.una) as usize
+            };
+            // a window probe sits just below SND.UNA and carries nothing
+            let probe = seq == self.send.una.wrapping_sub(1);
+            if probe {
+                offset = 0;
+                limit = 0;
+            }
             if let Some(closed_at) = self.closed_at {
                 if seq == closed_at.wrapping_add(1) {
                     // trying to write following FIN
@@ -257,12 +807,6 @@ impl Connection {
                     limit = 0;
                 }
             }
-            println!(
-                "using offset {} base {} in {:?}",
-                offset,
-                self.send.una,
-                self.unacked.as_slices()
-            );
             let (mut h, mut t) = self.unacked.as_slices();
             if h.len() >= offset {
                 h = &h[offset..];
@@ -290,7 +834,7 @@ impl Connection {
             let ip_header_ends_at = buf_len - unwritten.len();
 
             // postpone writing the tcp header because we need the payload as one contiguous slice to calculate the tcp checksum
-            unwritten = &mut unwritten[self.tcp.header_len() as usize..];
+            unwritten = &mut unwritten[self.tcp.header_len()..];
             let tcp_header_ends_at = buf_len - unwritten.len();
             
             // write out the payload          
@@ -311,11 +855,7 @@ impl Connection {
 
             let payload_ends_at = buf_len - unwritten.len();
              // finally we can calculate the tcp checksum and write out the tcp header
-            self.tcp.checksum = self
-                .tcp
-                .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payload_ends_at])
-
-                .expect("failed to compute checksum");
+            self.tcp.checksum = checksum(&self.tcp, &self.ip, &buf[tcp_header_ends_at..payload_ends_at]);
 
             let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
             let _ = self.tcp.write(&mut tcp_header_buf);
@@ -330,17 +870,123 @@ impl Connection {
                 self.tcp.fin = false;
             }
 
+            if next_seq != seq {
+                // the segment occupies sequence space, so it will be acked
+                if Self::wrapping_lt(seq, self.send.nxt) {
+                    // a retransmission, no RTT sample can be taken for what it covers
+                    self.timers
+                        .send_times
+                        .retain(|&s, _| Self::wrapping_lt(s, seq) || !Self::wrapping_lt(s, next_seq));
+                } else {
+                    self.timers.send_times.insert(seq, now);
+                }
+                if self.timers.retransmit.is_none() {
+                    // RFC 6298 S5.1
+                    self.timers.retransmit = Some(now + self.timers.rto);
+                }
+            }
             if Self::wrapping_lt(self.send.nxt, next_seq) {
                 self.send.nxt = next_seq;
             }
-            self.timers.send_times.insert(seq, time::Instant::now());
 
             nic.send(&buf[..payload_ends_at])?;
             Ok(payload_bytes)
         }
 
-    pub(crate) fn on_tick(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
+    /// Put the options on the segment about to be sent. A SYN carries all we support,
+    /// unless it answers a SYN that didn't, and once the handshake is done only
+    /// timestamps and SACK blocks are left, if negotiated.
+    fn set_options(&mut self, now: Instant) {
+        let offer = matches!(self.state, State::SynSent);
+        let mut options = Vec::with_capacity(4);
+        if self.tcp.syn {
+            options.push(TcpOptionElement::MaximumSegmentSize(own_mss(&self.ip) as u16));
+            if offer || self.send.wscale.is_some() {
+                options.push(TcpOptionElement::WindowScale(self.recv.wscale));
+            }
+            if self.sack {
+                options.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+            }
+        }
+        if (self.tcp.syn && offer) || self.timestamps.enabled {
+            options.push(TcpOptionElement::Timestamp(
+                self.timestamps.clock(now),
+                self.timestamps.recent,
+            ));
+        }
+        if self.sack && !self.tcp.syn {
+            // as many blocks as fit in the 40 bytes of options (RFC 2018 S3)
+            let max = if self.timestamps.enabled { 3 } else { 4 };
+            let mut blocks = self.reassembly.sack_blocks(max).into_iter();
+            if let Some(first) = blocks.next() {
+                let mut rest = [None; 3];
+                for (block, slot) in blocks.zip(&mut rest) {
+                    *slot = Some(block);
+                }
+                options.push(TcpOptionElement::SelectiveAcknowledgement(first, rest));
+            }
+        }
+        self.tcp.set_options(&options).expect("options fit in the header");
+    }
+
+    pub(crate) fn on_tick(&mut self, nic: &mut dyn PacketDevice, now: Instant) -> io::Result<()> {
+        if self.reset {
+            // <SEQ=SND.NXT><CTL=RST>
+            self.reset = false;
+            self.tcp.syn = false;
+            self.tcp.fin = false;
+            self.tcp.rst = true;
+            self.write(nic, self.send.nxt, 0, now)?;
+            return Ok(());
+        }
+        match self.timers.close {
+            Some(at) if at <= now => {
+                self.state = State::Closed;
+                self.timers.close = None;
+                return Ok(());
+            }
+            None if self.detached && matches!(self.state, State::FinWait2) => {
+                // nobody can close the connection if the peer never sends its FIN
+                self.timers.close = Some(now + 2 * MSL);
+            }
+            _ => {}
+        }
+
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            let window = self.receive_window();
+            let delayed_ack = matches!(self.timers.delayed_ack, Some(at) if at <= now);
+            if window > self.recv.wnd && window >= 2 * self.recv.wnd || delayed_ack {
+                // the user read enough to open the window considerably, tell the peer (RFC 793 S3.7);
+                // smaller updates ride along with the next ACK
+                self.write(nic, self.send.nxt, 0, now)?;
+            }
+        }
+
+        match self.keepalive {
+            Some(keepalive)
+                if matches!(self.state, State::Estab | State::CloseWait) && self.unacked.is_empty() =>
+            {
+                match self.timers.keepalive {
+                    None => self.timers.keepalive = Some(now + keepalive.idle),
+                    Some(at) if at <= now => {
+                        if self.timers.keepalive_probes >= keepalive.count {
+                            // the peer is gone
+                            self.abort(io::ErrorKind::TimedOut);
+                            return Ok(());
+                        }
+                        // like a window probe, SND.NXT-1 is old, so the peer has to ACK it
+                        self.write(nic, self.send.nxt.wrapping_sub(1), 0, now)?;
+                        self.timers.keepalive_probes += 1;
+                        self.timers.keepalive = Some(now + keepalive.interval);
+                    }
+                    Some(_) => {}
+                }
+            }
+            // not idle, the retransmission and persist timers find out whether the peer is there
+            _ => self.timers.keepalive = None,
+        }
+
+        if let State::FinWait2 | State::TimeWait | State::Closed = self.state {
             // we have shutdown our write side and the other side acked, no need to (re)transmit anything
             return Ok(());
         }
@@ -348,64 +994,367 @@ impl Connection {
         // eprintln!("ON TICK: state {:?} una {} nxt {} unacked {:?}",
         //           self.state, self.send.una, self.send.nxt, self.unacked);
 
-        // if has closed use that seq number
-        let nunacked_data = self.closed_at.unwrap_or(self.send.nxt).wrapping_sub(self.send.una);
-        let nunsent_data = self.unacked.len() as u32 - nunacked_data;
+        let should_retransmit = matches!(self.timers.retransmit, Some(at) if at <= now);
+        if should_retransmit {
+            self.timers.retries += 1;
+            let limit = if self.is_synchronized() { MAX_RETRIES } else { SYN_RETRIES };
+            if self.timers.retries > limit {
+                // the peer is gone or unreachable
+                self.abort(io::ErrorKind::TimedOut);
+                return Ok(());
+            }
+            self.timers.back_off();
+            self.timers.retransmit = Some(now + self.timers.rto);
+        }
 
+        if let State::SynSent | State::SynRcvd = self.state {
+            // only our SYN can be in flight until the handshake completes
+            if should_retransmit || self.send.nxt == self.send.iss {
+                self.tcp.syn = true;
+                self.write(nic, self.send.iss, 0, now)?;
+            }
+            return Ok(());
+        }
 
-        let waited_for = self
-            .timers
-            .send_times
-            .range(self.send.una..)
-            .next()
-            .map(|t| t.1.elapsed());
+        if should_retransmit {
+            // the whole window is presumed lost, start over from one segment (RFC 5681 S3.1),
+            // and resend the rest as the ACKs open the window again (RFC 6298 S5)
+            self.congestion.on_timeout(self.flight(), now);
+            // the receiver may have dropped what it SACKed, don't rely on it (RFC 2018 S8)
+            self.scoreboard.clear();
+            self.recover = Some(self.send.nxt);
+            self.dupacks = 0;
+            let high_rxt = self.retransmit(nic, now)?;
+            self.recovery = Some(Recovery::Timeout { high_rxt });
+            return Ok(());
+        }
 
-        // 等待时间大于1秒或者大于1.5倍 SRTT
-        let should_retransmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
-        } else {
-            false
-        };
+        // if has closed use that seq number
+        let nunacked_data = self.closed_at.unwrap_or(self.send.nxt).wrapping_sub(self.send.una);
+        let nunsent_data = self.unacked.len() as u32 - nunacked_data;
 
-        if should_retransmit {
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-            if resend < self.send.wnd as u32 && self.closed {
-                // can we include the FIN?
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        // the persist timer (RFC 1122 S4.2.2.17): with a zero window, the ACK that reopens it
+        // may get lost, so keep asking until the receiver has room for what is waiting
+        let waiting = nunsent_data > 0 || (self.closed && self.closed_at.is_none());
+        if self.send.wnd == 0 && waiting {
+            match self.timers.persist {
+                None => {
+                    self.timers.persist = Some(now + self.timers.rto);
+                    self.timers.probes = 0;
+                }
+                Some(at) if at <= now => {
+                    // a segment just below SND.UNA is never acceptable, so the receiver
+                    // answers it with an ACK carrying its current window
+                    self.write(nic, self.send.una.wrapping_sub(1), 0, now)?;
+                    self.timers.probes += 1;
+                    let backoff = self.timers.rto * 2u32.saturating_pow(self.timers.probes);
+                    self.timers.persist = Some(now + std::cmp::min(backoff, PERSIST_MAX));
+                }
+                Some(_) => {}
             }
-            self.write(nic, self.send.una, resend as usize)?;
         } else {
-            // we should send new data if we have new data and space in the window
+            self.timers.persist = None;
+        }
+
+        if let Some(Recovery::Sack { .. }) = self.recovery {
+            // the pipe decides what goes out, holes first
+            return self.send_recovery(nic, now);
+        }
+
+        // we should send new data if we have new data and space in the window
+        loop {
+            let nunacked_data = self.closed_at.unwrap_or(self.send.nxt).wrapping_sub(self.send.una);
+            let nunsent_data = self.unacked.len() - nunacked_data as usize;
             if nunsent_data == 0 && self.closed_at.is_some() {
                 return Ok(());
             }
 
-            let allowed = self.send.wnd as u32 - nunacked_data;
+            // what both the receiver and the network can take, the receiver's window
+            // may have shrunk below what is already in flight
+            let window = std::cmp::min(self.congestion_window(), self.send.wnd as usize);
+            let allowed = window.saturating_sub(self.flight());
             if allowed == 0 {
                 return Ok(());
             }
 
-            let send = std::cmp::min(nunsent_data, allowed);
-            if send < allowed && self.closed && self.closed_at.is_none() {
+            let send = std::cmp::min(nunsent_data, std::cmp::min(allowed, self.mss));
+            if send == nunsent_data && send < allowed && self.closed && self.closed_at.is_none() {
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
+            if send == 0 && !self.tcp.fin {
+                // nothing new to send, incoming segments are ACKed as they arrive
+                return Ok(());
+            }
+            if send < self.mss && !self.nodelay && !self.closed {
+                // Nagle's algorithm (RFC 1122 S4.2.3.4) in Minshall's variant: only one small
+                // segment may be unacked, more data can come along to fill up the next one
+                // meanwhile; the tail of a bulk transfer doesn't wait for a delayed ACK
+                if Self::wrapping_lt(self.send.una, self.small_sent) {
+                    return Ok(());
+                }
+                self.small_sent = self.send.nxt.wrapping_add(send as u32);
+            }
+
+            self.write(nic, self.send.nxt, send, now)?;
+        }
+    }
+
+    /// sequence space sent but not acked yet
+    fn flight(&self) -> usize {
+        self.send.nxt.wrapping_sub(self.send.una) as usize
+    }
+
+    /// how much may be in flight as far as the network is concerned
+    fn congestion_window(&self) -> usize {
+        match self.recovery {
+            Some(Recovery::Fast { inflation }) => self.congestion.window() + inflation,
+            _ => self.congestion.window(),
+        }
+    }
+
+    /// Resend the oldest unacked segment, together with our FIN if it fits in.
+    ///
+    /// Returns where the segment ends.
+    fn retransmit(&mut self, nic: &mut dyn PacketDevice, now: Instant) -> io::Result<u32> {
+        let wnd = self.send.wnd as usize;
+        let sacked = self
+            .scoreboard
+            .next_sacked(self.send.una)
+            .map_or(usize::MAX, |s| s.wrapping_sub(self.send.una) as usize);
+        let resend = [self.unacked.len(), self.mss, wnd, sacked].into_iter().min().unwrap();
+        if resend == self.unacked.len() && resend < wnd && self.closed {
+            self.tcp.fin = true;
+            self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        }
+        let fin = self.tcp.fin;
+        let sent = self.write(nic, self.send.una, resend, now)?;
+        Ok(self.send.una.wrapping_add(sent as u32 + fin as u32))
+    }
 
-            self.write(nic, self.send.nxt, send as usize)?;
+    /// Resend up to `max` bytes of sequence space from `seq`, stopping at SACKed data.
+    ///
+    /// Returns where the segment ends.
+    fn resend(&mut self, nic: &mut dyn PacketDevice, seq: u32, max: usize, now: Instant) -> io::Result<u32> {
+        let bound = self.scoreboard.next_sacked(seq).unwrap_or(self.send.nxt);
+        let len = std::cmp::min(max, bound.wrapping_sub(seq) as usize);
+        let offset = seq.wrapping_sub(self.send.una) as usize;
+        let data = std::cmp::min(len, self.unacked.len().saturating_sub(offset));
+        if data < len && self.closed_at.is_some() {
+            // the rest is our FIN
+            self.tcp.fin = true;
         }
+        let fin = self.tcp.fin;
+        let sent = self.write(nic, seq, data, now)?;
+        Ok(seq.wrapping_add(sent as u32 + fin as u32))
+    }
 
-        // if FIN, enter FIN-WAIT-1
+    /// bytes written by the user that haven't been sent once yet
+    fn unsent(&self) -> usize {
+        let nunacked_data = self.closed_at.unwrap_or(self.send.nxt).wrapping_sub(self.send.una);
+        self.unacked.len() - nunacked_data as usize
+    }
+
+    /// SetPipe() of RFC 6675 S4: how much of what is in flight is still in the network.
+    fn pipe(&self, high_rxt: u32) -> usize {
+        let mut pipe = 0;
+        for (start, end) in self.scoreboard.holes(self.send.una, self.send.nxt) {
+            if !self.scoreboard.is_lost(start, self.mss) {
+                pipe += end.wrapping_sub(start) as usize;
+            }
+            if Self::wrapping_lt(start, high_rxt) {
+                // retransmitted, and that copy is under way
+                let end = if Self::wrapping_lt(end, high_rxt) { end } else { high_rxt };
+                pipe += end.wrapping_sub(start) as usize;
+            }
+        }
+        pipe
+    }
+
+    /// NextSeg() of RFC 6675 S4: where the next segment sent during recovery starts.
+    fn next_segment(&self, high_rxt: u32) -> Option<u32> {
+        let high = self.scoreboard.high()?;
+        let mut unsacked = None;
+        for (start, end) in self.scoreboard.holes(self.send.una, high) {
+            let start = if Self::wrapping_lt(start, high_rxt) { high_rxt } else { start };
+            if !Self::wrapping_lt(start, end) {
+                continue;
+            }
+            if self.scoreboard.is_lost(start, self.mss) {
+                // (1) a hole that is lost and not retransmitted yet
+                return Some(start);
+            }
+            unsacked.get_or_insert(start);
+        }
+        let room = (self.send.wnd as usize).saturating_sub(self.flight());
+        if self.unsent() > 0 && room > 0 {
+            // (2) new data
+            return Some(self.send.nxt);
+        }
+        // (3) a hole that may just be delayed
+        unsacked
+    }
+
+    /// Send as much as the pipe leaves room for during SACK-based loss recovery (RFC 6675 S5).
+    fn send_recovery(&mut self, nic: &mut dyn PacketDevice, now: Instant) -> io::Result<()> {
+        while let Some(Recovery::Sack { high_rxt }) = self.recovery {
+            if self.pipe(high_rxt) + self.mss > self.congestion.window() {
+                break;
+            }
+            match self.next_segment(high_rxt) {
+                Some(seq) if Self::wrapping_lt(seq, self.send.nxt) => {
+                    let end = self.resend(nic, seq, self.mss, now)?;
+                    if end == seq {
+                        break;
+                    }
+                    self.recovery = Some(Recovery::Sack { high_rxt: end });
+                }
+                Some(_) => {
+                    let room = (self.send.wnd as usize).saturating_sub(self.flight());
+                    let send = [self.unsent(), room, self.mss].into_iter().min().unwrap();
+                    self.write(nic, self.send.nxt, send, now)?;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Resend what was lost to a timeout or dropped as too large, as far as the congestion
+    /// window allows; only the copies resent so far are in the network.
+    fn send_go_back_n(&mut self, nic: &mut dyn PacketDevice, now: Instant) -> io::Result<()> {
+        while let Some(Recovery::Timeout { high_rxt } | Recovery::PathMtu { high_rxt }) = self.recovery {
+            let from = if Self::wrapping_lt(high_rxt, self.send.una) { self.send.una } else { high_rxt };
+            if from.wrapping_sub(self.send.una) as usize + self.mss > self.congestion.window() {
+                break;
+            }
+            let recover = self.recover.unwrap_or(self.send.nxt);
+            // what the receiver SACKed made it after all
+            let Some(&(seq, _)) = self.scoreboard.holes(from, recover).first() else {
+                break;
+            };
+            let end = self.resend(nic, seq, self.mss, now)?;
+            if end == seq {
+                break;
+            }
+            if let Some(Recovery::Timeout { high_rxt } | Recovery::PathMtu { high_rxt }) = self.recovery.as_mut() {
+                *high_rxt = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// An ACK that acked `acked` bytes of new data moved SND.UNA: grow the window,
+    /// or move along the recovery (RFC 6582 S3.2).
+    fn on_new_ack(&mut self, nic: &mut dyn PacketDevice, acked: usize, now: Instant) -> io::Result<()> {
+        self.dupacks = 0;
+        let recovered = match self.recover {
+            Some(recover) => !Self::wrapping_lt(self.send.una, recover),
+            None => true,
+        };
+        match self.recovery.as_mut() {
+            Some(Recovery::Fast { inflation }) if !recovered => {
+                // partial ACK: the next hole is lost as well, resend it right away and
+                // deflate the window by what left the network
+                *inflation = inflation.saturating_sub(acked) + self.mss;
+                self.retransmit(nic, now)?;
+            }
+            Some(Recovery::Sack { .. }) if !recovered => {
+                self.send_recovery(nic, now)?;
+            }
+            Some(Recovery::Timeout { .. } | Recovery::PathMtu { .. }) if !recovered => {
+                self.congestion.on_ack(acked, self.timers.srtt, now);
+                self.send_go_back_n(nic, now)?;
+            }
+            Some(Recovery::Fast { .. } | Recovery::Sack { .. }) => {
+                // full ACK: back to cwnd = ssthresh
+                self.recovery = None;
+            }
+            _ => {
+                self.recovery = None;
+                self.congestion.on_ack(acked, self.timers.srtt, now);
+            }
+        }
+        if let Some(recover) = self.recover {
+            if Self::wrapping_lt(recover, self.send.una) {
+                // duplicate ACKs can no longer stem from before the last loss
+                self.recover = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// An ACK for SND.UNA that acks nothing new: after three in a row the segment
+    /// at SND.UNA is taken as lost (RFC 5681 S3.2), or as soon as SACK blocks tell
+    /// so (RFC 6675 S5).
+    fn on_duplicate_ack(&mut self, nic: &mut dyn PacketDevice, now: Instant) -> io::Result<()> {
+        self.dupacks += 1;
+        let lost = self.dupacks >= 3 || (self.sack && self.scoreboard.is_lost(self.send.una, self.mss));
+        match self.recovery.as_mut() {
+            Some(Recovery::Fast { inflation }) => {
+                // another segment has left the network
+                *inflation += self.mss;
+            }
+            Some(Recovery::Sack { .. }) => {
+                // the scoreboard changed, and with it the pipe
+                self.send_recovery(nic, now)?;
+            }
+            Some(Recovery::Timeout { .. } | Recovery::PathMtu { .. }) => {}
+            None if lost && self.recover.is_none() => {
+                self.congestion.on_loss(self.flight(), now);
+                self.recover = Some(self.send.nxt);
+                if self.sack {
+                    // the first segment goes out no matter what the pipe says
+                    let high_rxt = self.retransmit(nic, now)?;
+                    self.recovery = Some(Recovery::Sack { high_rxt });
+                    self.send_recovery(nic, now)?;
+                } else {
+                    self.recovery = Some(Recovery::Fast {
+                        inflation: 3 * self.mss,
+                    });
+                    self.retransmit(nic, now)?;
+                }
+            }
+            None => {}
+        }
         Ok(())
     }
 
     
     pub fn on_packet(&mut self,
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice,
+        nic: &mut dyn PacketDevice,
+        ip: &IpSlice,
         tcph: etherparse::TcpHeaderSlice,
-        data: &[u8]) -> io::Result<Available> {
+        data: &[u8],
+        now: Instant) -> io::Result<Available> {
+            match self.state {
+                State::SynSent => return self.on_syn_sent(nic, ip, tcph, data, now),
+                State::Closed => {
+                    // the connection no longer exists, treat it like a closed port
+                    send_rst(nic, ip, &tcph, data.len())?;
+                    return Ok(self.availability());
+                }
+                _ => {}
+            }
+
+            // the peer is alive, the connection is idle from now on at the most
+            self.timers.keepalive = None;
+            self.timers.keepalive_probes = 0;
+
+            let options = Options::parse(&tcph);
+            let tsval = options.timestamp.filter(|_| self.timestamps.enabled).map(|(tsval, _)| tsval);
+            if let Some(tsval) = tsval {
+                if !tcph.rst()
+                    && Self::wrapping_lt(tsval, self.timestamps.recent)
+                    && now.saturating_duration_since(self.timestamps.recent_at) < PAWS_IDLE
+                {
+                    // PAWS (RFC 7323 S5.3): an old duplicate, maybe one from before the
+                    // sequence numbers wrapped around, which the checks below can't tell
+                    self.write(nic, self.send.nxt, 0, now)?;
+                    return Ok(self.availability());
+                }
+            }
+
             // first, check that sequence numbers are valid (RFC 793 S3.3)
             let seqn = tcph.sequence_number();
             let mut slen = data.len() as u32;
@@ -415,50 +1364,79 @@ impl Connection {
                 slen += 1;
             }
             
-            let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+            let wend = self.recv.nxt.wrapping_add(self.recv.wnd);
+
+            if tcph.rst() {
+                // RFC 5961 S3.2: only an RST exactly at RCV.NXT resets the connection,
+                // one elsewhere in the window gets a challenge ACK, anything else is dropped
+                if seqn == self.recv.nxt {
+                    self.abort(io::ErrorKind::ConnectionReset);
+                } else if Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
+                    self.write(nic, self.send.nxt, 0, now)?;
+                }
+                return Ok(self.availability());
+            }
 
             let okay = if slen == 0 {
                 // zero-length segment has separate rules for acceptance
                 if self.recv.wnd == 0 {
-                    if seqn != self.recv.nxt {
-                        false
-                    } else {
-                        true
-                    }
-                } else if !Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                    false
+                    seqn == self.recv.nxt
                 } else {
-                    true
+                    Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
                 }
+            } else if self.recv.wnd == 0 {
+                false
             } else {
-                if self.recv.wnd == 0 {
-                    false
-                } else if !Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                    && !Self::is_between_wrapped(
+                // check both window start and end is valid
+                Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
+                    || Self::is_between_wrapped(
                         self.recv.nxt.wrapping_sub(1),
                         seqn.wrapping_add(slen - 1),
                         wend,
-                    ) // check both window start and end is valid
-            {
-                false
-            } else {
-                true
-            }
+                    )
             };
 
             // seq check not valid    
+            let mut data = data;
+            let mut fin = tcph.fin();
             if !okay {
                 eprintln!("NOT OKAY");
-                self.write(nic,self.send.nxt,0)?;
-                return Ok(self.availability());
+                self.write(nic,self.send.nxt, 0, now)?;
+                if self.recv.wnd != 0 || seqn != self.recv.nxt {
+                    return Ok(self.availability());
+                }
+                // with a zero window we must still process the ACK of a segment at RCV.NXT
+                // (RFC 793 S3.7), the data in it doesn't fit and is dropped; this is how a
+                // window probe gets through
+                fin &= data.is_empty();
+                data = &[];
+            } else if let Some(tsval) = tsval {
+                if !Self::wrapping_lt(self.timestamps.last_ack_sent, seqn)
+                    && !Self::wrapping_lt(tsval, self.timestamps.recent)
+                {
+                    // the timestamp to echo is that of the oldest segment our next ACK
+                    // acknowledges (RFC 7323 S4.3)
+                    self.timestamps.recent = tsval;
+                    self.timestamps.recent_at = now;
+                }
             }
             // move to handle in estab/fin_wait state
             //self.recv.nxt = seqn.wrapping_add(slen);
 
+            if tcph.syn() && self.is_synchronized() {
+                // RFC 5961 S4.2: never reset on a SYN, send a challenge ACK instead
+                self.write(nic, self.send.nxt, 0, now)?;
+                return Ok(self.availability());
+            }
+
             if !tcph.ack() {
-                // got SYN part of initial handshake
-                assert!(data.is_empty());
-                self.recv.nxt = seqn.wrapping_add(1);
+                if tcph.syn() {
+                    // SYN-RECEIVED: the peer sent its SYN again, so our SYN-ACK got lost;
+                    // answer once more, what data came along is dropped until the handshake is done
+                    self.tcp.syn = true;
+                    self.write(nic, self.send.iss, 0, now)?;
+                }
+                // anything else must carry an ACK, drop it (RFC 793 S3.9)
                 return Ok(self.availability());
             }
 
@@ -471,18 +1449,33 @@ impl Connection {
                 ) {
                     // must have ACKed our SYN, since we detected at least one acked byte,
                     // and we have only sent one byte (the SYN).
-                    self.state = State::Estab;
+                    self.state = if self.closed { State::FinWait1 } else { State::Estab };
+                    self.send.wnd = self.send_window(&tcph);
+                    self.send.wl1 = seqn;
+                    self.send.wl2 = ackn;
                 } else {
-                    // TODO: <SEQ=SEG.ACK><CTL=RST>
+                    // <SEQ=SEG.ACK><CTL=RST>
+                    send_rst(nic, ip, &tcph, data.len())?;
+                    return Ok(self.availability());
                 }
             }
 
-            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                if Self::is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                    println!(
-                        "ack for {} (last: {}); prune in {:?}",
-                        ackn, self.send.una, self.unacked
-                    );
+            if Self::wrapping_lt(self.send.nxt, ackn) {
+                // the ACK acks something not yet sent: ACK and drop the segment
+                self.write(nic, self.send.nxt, 0, now)?;
+                return Ok(self.availability());
+            }
+
+            if let State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait
+            | State::Closing
+            | State::LastAck = self.state
+            {
+                let advanced = Self::is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1));
+                let acked = ackn.wrapping_sub(self.send.una) as usize;
+                if advanced {
                     if ! self.unacked.is_empty() {
                         let data_start = if self.send.una == self.send.iss {
                             // send.una hasn't been updated yet with ACK for our SYN, so data starts just beyond it
@@ -492,81 +1485,258 @@ impl Connection {
                         };
                         let acked_data_end = std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
                         self.unacked.drain(..acked_data_end);
-                        
-                        let old = std::mem::replace(&mut self.timers.send_times,BTreeMap::new());
-                        let una = self.send.una;
-                        let mut srtt =& mut self.timers.srtt;
-                        self.timers.send_times.extend(old.into_iter().filter_map(|(seq,sent)|{
-                            if Self::is_between_wrapped(una, seq, ackn) {
-                                *srtt = 0.8 * *srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
-                                None
-                            } else {
-                                Some((seq, sent))
-                            }
-                        }));
-                        
                     }
+                    self.on_acked(ackn, options.timestamp, now);
                     self.send.una = ackn;
+                    self.scoreboard.advance(ackn);
+                }
+                if self.sack {
+                    for block in options.sack_blocks() {
+                        self.scoreboard.insert(self.send.una, self.send.nxt, block);
+                    }
+                }
+                if advanced {
+                    if self.is_synchronized() {
+                        self.on_new_ack(nic, acked, now)?;
+                    }
+                } else if ackn == self.send.una
+                    && self.send.nxt != self.send.una
+                    && data.is_empty()
+                    && !tcph.fin()
+                    && self.send_window(&tcph) == self.send.wnd
+                {
+                    self.on_duplicate_ack(nic, now)?;
                 }
 
-                // TODO: prune self.unacked
-                // TODO: if unacked empty and waiting flush, notify
-                // TODO: update window
+                if !Self::wrapping_lt(ackn, self.send.una)
+                    && !Self::wrapping_lt(self.send.nxt, ackn)
+                    && (Self::wrapping_lt(self.send.wl1, seqn)
+                        || (self.send.wl1 == seqn && !Self::wrapping_lt(ackn, self.send.wl2)))
+                {
+                    // SND.UNA =< SEG.ACK =< SND.NXT, and the segment is not older than the one
+                    // the window was last taken from (RFC 793 S3.9), so a reordered
+                    // segment can't bring back an old window
+                    self.send.wnd = self.send_window(&tcph);
+                    self.send.wl1 = seqn;
+                    self.send.wl2 = ackn;
+                }
 
             }
 
             if let Some(closed_at) = self.closed_at {
                 if self.send.una == closed_at.wrapping_add(1) {
                     // our FIN has been ACKed!
-                    self.state = State::FinWait2;
+                    match self.state {
+                        State::FinWait1 => self.state = State::FinWait2,
+                        State::Closing => self.time_wait(now),
+                        State::LastAck => self.state = State::Closed,
+                        _ => {}
+                    }
                 }
 
             }
 
             if !data.is_empty() {
                 if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                    let mut unread_data_at = (self.recv.nxt.wrapping_sub(seqn)) as usize;
-                    if unread_data_at > data.len() {
-                        // we must have received a re-transmitted FIN that we have already seen
-                        // nxt points to beyond the fin, but the fin is not in data!
-                        assert_eq!(unread_data_at, data.len() + 1);
-                        unread_data_at = 0;
-                    }
+                    // only keep what fits in the window
+                    let room = wend.wrapping_sub(seqn) as usize;
+                    let data = &data[..std::cmp::min(data.len(), room)];
+
+                    // out of order data, and data filling a gap, is acked right away
+                    // (RFC 5681 S4.2), so the sender learns about the hole and its repair
+                    let mut immediate = !self.reassembly.is_empty();
+                    if Self::wrapping_lt(self.recv.nxt, seqn) {
+                        // ahead of a gap: hold on to it, and send a duplicate ACK
+                        self.reassembly.insert(seqn, data);
+                        immediate = true;
+                    } else {
+                        // skip the bytes we already have (a retransmission overlapping new data)
+                        let unread_data_at = std::cmp::min(
+                            self.recv.nxt.wrapping_sub(seqn) as usize,
+                            data.len(),
+                        );
+
+//                        eprintln!("read data {} of {:?}",unread_data_at,data);
+                        self.incoming.extend(&data[unread_data_at..]);
 
-//                    eprintln!("read data {} of {:?}",unread_data_at,data);
-                    self.incoming.extend(&data[unread_data_at..]);
+                        // Once the TCP takes responsibility for the data it advances
+                        // RCV.NXT over the data accepted, and adjusts RCV.WND as
+                        // apporopriate to the current buffer availability.  The total of
+                        // RCV.NXT and RCV.WND should not be reduced.
+                        let accepted = data.len() - unread_data_at;
+                        self.recv.nxt = self.recv.nxt.wrapping_add(accepted as u32);
+                        self.recv.wnd = self.recv.wnd.saturating_sub(accepted as u32);
+                        self.ack_pending += accepted;
+                        // a duplicate means our ACK got lost, repeat it
+                        immediate |= accepted == 0;
 
-                    // Once the TCP takes responsibility for the data it advances
-                    // RCV.NXT over the data accepted, and adjusts RCV.WND as
-                    // apporopriate to the current buffer availability.  The total of
-                    // RCV.NXT and RCV.WND should not be reduced.
-                    self.recv.nxt = seqn.wrapping_add(data.len() as u32);
-                    // Send an acknowledgment of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-                    self.write(nic, self.send.nxt,0)?;
+                        // the gap in front of queued segments may be filled now
+                        while let Some(run) = self.reassembly.pop(self.recv.nxt) {
+                            self.recv.nxt = self.recv.nxt.wrapping_add(run.len() as u32);
+                            self.recv.wnd = self.recv.wnd.saturating_sub(run.len() as u32);
+                            self.incoming.extend(run);
+                        }
+                    }
+
+                    if fin {
+                        // a FIN is acked below together with the data
+                    } else if immediate || self.ack_pending >= 2 * self.mss {
+                        // Send an acknowledgment of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+                        // at least for every second full-sized segment (RFC 1122 S4.2.3.2)
+                        self.write(nic, self.send.nxt, 0, now)?;
+                    } else if self.timers.delayed_ack.is_none() {
+                        // otherwise wait a little, the user may answer and the ACK can ride along
+                        self.timers.delayed_ack = Some(now + DELAYED_ACK);
+                    }
                 }
             }
-            
-            if tcph.fin() {
+            if self.detached {
+                // nobody is going to read it, don't let it close the window
+                self.incoming.clear();
+            }
+
+            if fin && !self.is_rcv_closed() {
+                // the FIN occupies the sequence number right after the data,
+                // but it may have arrived ahead of a gap as well
+                self.reassembly.insert_fin(seqn.wrapping_add(data.len() as u32));
+            }
+            if self.reassembly.take_fin(self.recv.nxt) {
                 match self.state {
+                    State::SynRcvd | State::Estab => {
+                        // the peer is done sending, wait for our user to close
+                        self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                        self.write(nic,self.send.nxt, 0, now)?;
+                        self.state = State::CloseWait;
+                    }
+                    State::FinWait1 => {
+                        // simultaneous close, our FIN is not acked yet
+                        self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                        self.write(nic,self.send.nxt, 0, now)?;
+                        self.state = State::Closing;
+                    }
                     State::FinWait2 => {
                         // we're done with the connection!
                         self.recv.nxt = self.recv.nxt.wrapping_add(1);
-                        self.write(nic,self.send.nxt,0)?;
-                        self.state = State::TimeWait;
+                        self.write(nic,self.send.nxt, 0, now)?;
+                        self.time_wait(now);
                     }
-                    _ => unimplemented!(),
+                    _ => {}
+                }
+            } else if fin {
+                // a retransmitted FIN whose ACK got lost, or a FIN beyond a gap in the data
+                self.write(nic,self.send.nxt, 0, now)?;
+                if let State::TimeWait = self.state {
+                    self.time_wait(now);
                 }
             }
             Ok(self.availability())
         }
 
+    /// SEGMENT ARRIVES in the SYN-SENT state (RFC 793 S3.9)
+    fn on_syn_sent(&mut self,
+        nic: &mut dyn PacketDevice,
+        ip: &IpSlice,
+        tcph: etherparse::TcpHeaderSlice,
+        data: &[u8],
+        now: Instant) -> io::Result<Available> {
+            let ackn = tcph.acknowledgment_number();
+            if tcph.ack()
+                && !Self::is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1))
+            {
+                // the ACK does not cover our SYN, so this is not an answer to us: <SEQ=SEG.ACK><CTL=RST>
+                send_rst(nic, ip, &tcph, data.len())?;
+                return Ok(self.availability());
+            }
+
+            if tcph.rst() {
+                // only an RST that acks our SYN is acceptable
+                if tcph.ack() {
+                    self.abort(io::ErrorKind::ConnectionRefused);
+                }
+                return Ok(self.availability());
+            }
+
+            if !tcph.syn() {
+                // neither SYN nor RST: drop the segment
+                return Ok(self.availability());
+            }
+
+            self.recv.irs = tcph.sequence_number();
+            self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+            // the window of a SYN is never scaled, and it holds for a simultaneous open as well
+            self.send.wnd = tcph.window_size() as u32;
+            self.send.wl1 = tcph.sequence_number();
+            self.send.wl2 = ackn;
+            let options = Options::parse(&tcph);
+            self.negotiate(&options, now);
+            if tcph.ack() {
+                self.on_acked(ackn, options.timestamp, now);
+                self.send.una = ackn;
+            }
+
+            self.tcp.ack = true;
+            if self.send.una != self.send.iss {
+                // our SYN has been ACKed, answer with <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+                self.state = if self.closed { State::FinWait1 } else { State::Estab };
+                self.write(nic, self.send.nxt, 0, now)?;
+            } else {
+                // simultaneous open: <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK>
+                self.state = State::SynRcvd;
+                self.tcp.syn = true;
+                self.write(nic, self.send.iss, 0, now)?;
+            }
+            Ok(self.availability())
+        }
+
+    /// An ACK moved SND.UNA up to `ackn`: sample the RTT and restart the
+    /// retransmission timer, or stop it if nothing is left in flight (RFC 6298 S5.2, S5.3).
+    ///
+    /// With timestamps the ACK's TSecr tells the round trip time, even of a retransmission.
+    fn on_acked(&mut self, ackn: u32, timestamp: Option<(u32, u32)>, now: Instant) {
+        let mut latest = None;
+        self.timers.send_times.retain(|&seq, &mut sent| {
+            if Self::wrapping_lt(seq, ackn) {
+                latest = std::cmp::max(latest, Some(sent));
+                false
+            } else {
+                true
+            }
+        });
+        match timestamp {
+            Some((_, tsecr)) if self.timestamps.enabled => {
+                self.timers.sample(self.timestamps.rtt(tsecr, now));
+            }
+            _ => {
+                if let Some(sent) = latest {
+                    self.timers.sample(now.saturating_duration_since(sent));
+                }
+            }
+        }
+
+        self.timers.retries = 0;
+        self.timers.retransmit = if ackn == self.send.nxt {
+            None
+        } else {
+            Some(now + self.timers.rto)
+        };
+    }
+
     pub(crate) fn close(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error() {
+            return Err(e);
+        }
         self.closed = true;
         match self.state {
+            State::SynSent => {
+                // the FIN is queued until the handshake completes
+            }
             State::SynRcvd | State::Estab => {
                 self.state = State::FinWait1;
             }
-            State::FinWait1 | State::FinWait2 => {}
+            State::CloseWait => {
+                self.state = State::LastAck;
+            }
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck | State::TimeWait => {}
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::NotConnected,
@@ -591,6 +1761,7 @@ impl Connection {
     fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
         Self::wrapping_lt(start, x) && Self::wrapping_lt(x, end)
     }
+    #[allow(dead_code)]
     fn is_between_wrapped_old(start: u32, x: u32, end: u32) -> bool {
         use std::cmp::Ordering;
         match start.cmp(&x) {
@@ -652,3 +1823,150 @@ impl Connection {
         true
     }
 }
+
+/// the smallest shift that lets us advertise all of `buffer` (RFC 7323 S2.3)
+fn window_scale(buffer: usize) -> u8 {
+    let mut shift = 0;
+    while shift < MAX_WINDOW_SCALE && buffer >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+}
+
+/// the MSS we announce, what fits in the MTU behind `ip` and a TCP header without options
+fn own_mss(ip: &IpHeaders) -> usize {
+    MTU - ip.header_len() - TCP_HEADER_LEN
+}
+
+/// the MSS to assume until the peer tells us its own
+fn default_mss(ip: &IpHeaders) -> usize {
+    match ip {
+        IpHeaders::Ipv4(..) => DEFAULT_MSS,
+        IpHeaders::Ipv6(..) => DEFAULT_MSS_V6,
+    }
+}
+
+/// The IP header of the segments from `local` to `remote`, the payload length is set
+/// for every segment.
+fn ip_header(local: IpAddr, remote: IpAddr) -> IpHeaders {
+    match (local, remote) {
+        (IpAddr::V4(local), IpAddr::V4(remote)) => IpHeaders::Ipv4(
+            etherparse::Ipv4Header::new(0, 64, IpNumber::TCP, local.octets(), remote.octets()).unwrap(),
+            Default::default(),
+        ),
+        (IpAddr::V6(local), IpAddr::V6(remote)) => IpHeaders::Ipv6(
+            etherparse::Ipv6Header {
+                next_header: IpNumber::TCP,
+                hop_limit: 64,
+                source: local.octets(),
+                destination: remote.octets(),
+                ..Default::default()
+            },
+            Default::default(),
+        ),
+        _ => panic!("{} and {} are not of the same IP version", local, remote),
+    }
+}
+
+/// the TCP checksum, over the pseudo-header of IPv4 or of IPv6 (RFC 8200 S8.1)
+fn checksum(tcp: &etherparse::TcpHeader, ip: &IpHeaders, payload: &[u8]) -> u16 {
+    match ip {
+        IpHeaders::Ipv4(h, _) => tcp.calc_checksum_ipv4(h, payload),
+        IpHeaders::Ipv6(h, _) => tcp.calc_checksum_ipv6(h, payload),
+    }
+    .expect("failed to compute checksum")
+}
+
+/// whether the checksum of a segment that arrived in `ip` is right
+pub(crate) fn checksum_ok(ip: &IpSlice, tcph: &etherparse::TcpHeaderSlice, payload: &[u8]) -> bool {
+    let sum = match ip {
+        IpSlice::Ipv4(v4) => tcph.calc_checksum_ipv4(&v4.header(), payload),
+        IpSlice::Ipv6(v6) => tcph.calc_checksum_ipv6(&v6.header(), payload),
+    };
+    sum.ok() == Some(tcph.checksum())
+}
+
+/// Reset Generation (RFC 793 S3.4): answer a segment that does not belong to any connection.
+///
+/// If the segment has an ACK the reset takes its sequence number from the ACK field,
+/// otherwise the reset has sequence number zero and acks the whole segment.
+/// An incoming RST is never answered.
+pub(crate) fn send_rst(nic: &mut dyn PacketDevice,
+    ip: &IpSlice,
+    tcph: &etherparse::TcpHeaderSlice,
+    data_len: usize) -> io::Result<()> {
+        if tcph.rst() {
+            return Ok(());
+        }
+
+        let mut tcp = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+        tcp.rst = true;
+        if tcph.ack() {
+            tcp.sequence_number = tcph.acknowledgment_number();
+        } else {
+            let mut slen = data_len as u32;
+            if tcph.syn() {
+                slen += 1;
+            }
+            if tcph.fin() {
+                slen += 1;
+            }
+            tcp.ack = true;
+            tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(slen);
+        }
+
+        let mut ip = ip_header(ip.destination_addr(), ip.source_addr());
+        let _ = ip.set_payload_len(tcp.header_len());
+        tcp.checksum = checksum(&tcp, &ip, &[]);
+
+        let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len());
+        ip.write(&mut buf).map_err(io::Error::other)?;
+        tcp.write(&mut buf)?;
+        nic.send(&buf)?;
+        Ok(())
+    }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn rto_follows_rfc_6298() {
+        let mut t = Timers::new();
+        assert_eq!(t.rto, MIN_RTO);
+
+        // first sample: SRTT = R, RTTVAR = R/2, RTO = R + 4 * RTTVAR
+        t.sample(Duration::from_millis(800));
+        assert_eq!(t.srtt, Some(Duration::from_millis(800)));
+        assert_eq!(t.rttvar, Duration::from_millis(400));
+        assert_eq!(t.rto, Duration::from_millis(2400));
+
+        // RTTVAR = 3/4 * 400 + 1/4 * |800 - 400|, SRTT = 7/8 * 800 + 1/8 * 400
+        t.sample(Duration::from_millis(400));
+        assert_eq!(t.rttvar, Duration::from_millis(400));
+        assert_eq!(t.srtt, Some(Duration::from_millis(750)));
+        assert_eq!(t.rto, Duration::from_millis(2350));
+
+        // a fast, steady link is still clamped to the minimum
+        for _ in 0..50 {
+            t.sample(Duration::from_millis(10));
+        }
+        assert_eq!(t.rto, MIN_RTO);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut t = Timers::new();
+        t.back_off();
+        assert_eq!(t.rto, 2 * MIN_RTO);
+        for _ in 0..10 {
+            t.back_off();
+        }
+        assert_eq!(t.rto, MAX_RTO);
+
+        // a new sample computes the RTO from scratch again
+        t.sample(Duration::from_millis(500));
+        assert_eq!(t.rto, Duration::from_millis(1500));
+    }
+}
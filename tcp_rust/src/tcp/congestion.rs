@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+mod cubic;
+
+pub use cubic::Cubic;
+
+/// A congestion control algorithm, owned by a single connection.
+///
+/// Implementations only keep the congestion window; detecting losses and running fast
+/// recovery is up to the connection, which tells the algorithm what happened.
+pub trait CongestionControl: Send {
+    /// how many bytes may be in flight
+    fn window(&self) -> usize;
+
+    /// `acked` bytes of new data were acknowledged outside of fast recovery, `srtt` is
+    /// the smoothed round trip time if there is one yet
+    fn on_ack(&mut self, acked: usize, srtt: Option<Duration>, now: Instant);
+
+    /// three duplicate ACKs: a segment was lost, `flight` bytes were outstanding
+    fn on_loss(&mut self, flight: usize, now: Instant);
+
+    /// the retransmission timer expired with `flight` bytes outstanding
+    fn on_timeout(&mut self, flight: usize, now: Instant);
+}
+
+/// Creates the congestion control of a new connection, given its sender maximum
+/// segment size. `NewReno::boxed` and `Cubic::boxed` are the ones that ship.
+pub type CongestionFactory = fn(usize) -> Box<dyn CongestionControl>;
+
+/// NewReno congestion control (RFC 5681, RFC 6582), the default.
+pub struct NewReno {
+    /// sender maximum segment size
+    smss: usize,
+    /// congestion window, in bytes
+    cwnd: usize,
+    /// slow start threshold, in bytes
+    ssthresh: usize,
+}
+
+impl NewReno {
+    pub fn new(smss: usize) -> Self {
+        NewReno {
+            smss,
+            cwnd: initial_window(smss),
+            // arbitrarily high, so the first loss ends slow start (RFC 5681 S3.1)
+            ssthresh: usize::MAX,
+        }
+    }
+
+    pub fn boxed(smss: usize) -> Box<dyn CongestionControl> {
+        Box::new(Self::new(smss))
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, acked: usize, _srtt: Option<Duration>, _now: Instant) {
+        if self.cwnd < self.ssthresh {
+            // slow start: two SMSS per ACK at most, so a delayed ACK counts for both segments
+            // but stretch ACKs don't cause bursts (RFC 3465 S2.2)
+            self.cwnd += std::cmp::min(acked, 2 * self.smss);
+        } else {
+            // congestion avoidance: about one SMSS per round trip
+            self.cwnd += std::cmp::max(1, self.smss * self.smss / self.cwnd);
+        }
+    }
+
+    // RFC 5681 S3.2
+    fn on_loss(&mut self, flight: usize, _now: Instant) {
+        self.ssthresh = std::cmp::max(flight / 2, 2 * self.smss);
+        self.cwnd = self.ssthresh;
+    }
+
+    // RFC 5681 S3.1
+    fn on_timeout(&mut self, flight: usize, _now: Instant) {
+        self.ssthresh = std::cmp::max(flight / 2, 2 * self.smss);
+        // the loss window
+        self.cwnd = self.smss;
+    }
+}
+
+/// the initial window of RFC 3390
+pub(crate) fn initial_window(smss: usize) -> usize {
+    std::cmp::min(4 * smss, std::cmp::max(2 * smss, 4380))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMSS: usize = 1000;
+
+    #[test]
+    fn slow_start_then_congestion_avoidance() {
+        let now = Instant::now();
+        let mut cc = NewReno::new(SMSS);
+        assert_eq!(cc.window(), 4 * SMSS);
+
+        // every ACK of a full segment grows the window by a segment
+        for _ in 0..4 {
+            cc.on_ack(SMSS, None, now);
+        }
+        assert_eq!(cc.window(), 8 * SMSS);
+
+        cc.on_loss(8 * SMSS, now);
+        assert_eq!(cc.window(), 4 * SMSS);
+
+        // above ssthresh, a window's worth of ACKs adds one segment
+        for _ in 0..4 {
+            cc.on_ack(SMSS, None, now);
+        }
+        assert!(cc.window() > 4 * SMSS && cc.window() <= 5 * SMSS);
+    }
+
+    #[test]
+    fn timeout_collapses_the_window() {
+        let now = Instant::now();
+        let mut cc = NewReno::new(SMSS);
+        cc.on_timeout(3 * SMSS, now);
+        assert_eq!(cc.window(), SMSS);
+        assert_eq!(cc.ssthresh, 2 * SMSS);
+
+        // slow start again up to the new threshold
+        cc.on_ack(SMSS, None, now);
+        assert_eq!(cc.window(), 2 * SMSS);
+        cc.on_ack(SMSS, None, now);
+        assert_eq!(cc.window(), 2 * SMSS + SMSS / 2);
+    }
+}
@@ -0,0 +1,176 @@
+use super::{initial_window, CongestionControl};
+use std::time::{Duration, Instant};
+
+/// the scaling constant, in segments per second cubed
+const C: f64 = 0.4;
+/// the multiplicative decrease factor
+const BETA: f64 = 0.7;
+
+/// CUBIC congestion control (RFC 8312).
+///
+/// After a loss the window grows along a cubic function of the time since then: fast
+/// while far below the window the loss happened at, flat around it, and fast again
+/// beyond. That makes it less sensitive to the round trip time than NewReno, and it
+/// recovers a lot quicker on long fat links that occasionally lose a segment.
+pub struct Cubic {
+    /// sender maximum segment size
+    smss: usize,
+    /// congestion window, in segments
+    cwnd: f64,
+    /// slow start threshold, in segments
+    ssthresh: f64,
+    /// the window just before the last reduction, in segments
+    w_max: f64,
+    /// `w_max` of the reduction before, for fast convergence
+    w_last_max: f64,
+    /// when the current congestion avoidance epoch started
+    epoch: Option<Instant>,
+    /// seconds it takes the cubic function to get back to `w_max`
+    k: f64,
+    /// the window a NewReno sender would have by now, in segments
+    w_est: f64,
+}
+
+impl Cubic {
+    pub fn new(smss: usize) -> Self {
+        Cubic {
+            smss,
+            cwnd: initial_window(smss) as f64 / smss as f64,
+            ssthresh: f64::INFINITY,
+            w_max: 0.0,
+            w_last_max: 0.0,
+            epoch: None,
+            k: 0.0,
+            w_est: 0.0,
+        }
+    }
+
+    pub fn boxed(smss: usize) -> Box<dyn CongestionControl> {
+        Box::new(Self::new(smss))
+    }
+
+    /// remember where the loss happened and set ssthresh (RFC 8312 S4.5, S4.6)
+    fn reduce(&mut self, flight: usize) {
+        self.epoch = None;
+        if self.cwnd < self.w_last_max {
+            // fast convergence: we lost before reaching the last maximum, so another
+            // flow probably came along; leave some room for it
+            self.w_last_max = self.cwnd;
+            self.w_max = self.cwnd * (1.0 + BETA) / 2.0;
+        } else {
+            self.w_last_max = self.cwnd;
+            self.w_max = self.cwnd;
+        }
+        let flight = flight as f64 / self.smss as f64;
+        self.ssthresh = f64::max(flight * BETA, 2.0);
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn window(&self) -> usize {
+        std::cmp::max((self.cwnd * self.smss as f64) as usize, self.smss)
+    }
+
+    fn on_ack(&mut self, acked: usize, srtt: Option<Duration>, now: Instant) {
+        if self.cwnd < self.ssthresh {
+            // slow start as in NewReno
+            self.cwnd += std::cmp::min(acked, 2 * self.smss) as f64 / self.smss as f64;
+            return;
+        }
+
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                // first ACK in congestion avoidance since the last reduction
+                if self.cwnd < self.w_max {
+                    self.k = ((self.w_max - self.cwnd) / C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = self.cwnd;
+                }
+                self.w_est = self.cwnd;
+                self.epoch = Some(now);
+                now
+            }
+        };
+
+        let segments = acked as f64 / self.smss as f64;
+        // aim for where the cubic function will be a round trip from now (S4.1)
+        let t = (now - epoch + srtt.unwrap_or_default()).as_secs_f64();
+        let target = C * (t - self.k).powi(3) + self.w_max;
+
+        // TCP-friendly region (S4.2): never grow slower than NewReno would
+        self.w_est += 3.0 * (1.0 - BETA) / (1.0 + BETA) * segments / self.cwnd;
+        if target < self.w_est {
+            self.cwnd = f64::max(self.cwnd, self.w_est);
+        } else if target > self.cwnd {
+            // concave and convex region (S4.3, S4.4), at most 1.5 times per round trip
+            let target = f64::min(target, 1.5 * self.cwnd);
+            self.cwnd += (target - self.cwnd) / self.cwnd * segments;
+        }
+    }
+
+    fn on_loss(&mut self, flight: usize, _now: Instant) {
+        self.reduce(flight);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self, flight: usize, _now: Instant) {
+        self.reduce(flight);
+        // the loss window, then slow start up to ssthresh
+        self.cwnd = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMSS: usize = 1000;
+    const RTT: Duration = Duration::from_millis(100);
+
+    /// acks a whole window every round trip for `duration`
+    fn run(cc: &mut Cubic, now: &mut Instant, duration: Duration) {
+        let end = *now + duration;
+        while *now < end {
+            *now += RTT;
+            for _ in 0..cc.window() / SMSS {
+                cc.on_ack(SMSS, Some(RTT), *now);
+            }
+        }
+    }
+
+    #[test]
+    fn regrows_to_the_window_of_the_last_loss() {
+        let mut now = Instant::now();
+        let mut cc = Cubic::new(SMSS);
+        cc.cwnd = 100.0;
+        cc.ssthresh = 100.0;
+
+        cc.on_loss(100 * SMSS, now);
+        assert_eq!(cc.window(), 70 * SMSS);
+
+        // K = cbrt(30 / 0.4) is a little over four seconds, NewReno would take five from 50
+        run(&mut cc, &mut now, Duration::from_secs(4));
+        assert!(cc.window() > 95 * SMSS && cc.window() <= 100 * SMSS, "{}", cc.window());
+        // then it hardly grows for a while around the old maximum
+        run(&mut cc, &mut now, Duration::from_secs(1));
+        assert!(cc.window() > 99 * SMSS && cc.window() < 102 * SMSS, "{}", cc.window());
+        // before probing beyond it again
+        run(&mut cc, &mut now, Duration::from_secs(3));
+        assert!(cc.window() > 110 * SMSS, "{}", cc.window());
+    }
+
+    #[test]
+    fn timeout_restarts_slow_start() {
+        let now = Instant::now();
+        let mut cc = Cubic::new(SMSS);
+        cc.cwnd = 10.0;
+        cc.ssthresh = 10.0;
+
+        cc.on_timeout(10 * SMSS, now);
+        assert_eq!(cc.window(), SMSS);
+        cc.on_ack(SMSS, Some(RTT), now);
+        assert_eq!(cc.window(), 2 * SMSS);
+    }
+}
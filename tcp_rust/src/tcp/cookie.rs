@@ -0,0 +1,101 @@
+use super::siphash::{push_addr, siphash24};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// a cookie is good in the time slot it was made in and the next one
+const SLOT: Duration = Duration::from_secs(64);
+/// the MSS values a cookie can encode
+const MSS_TABLE: [u16; 8] = [536, 1024, 1220, 1300, 1400, 1440, 1452, 1460];
+/// the bits of a cookie that hold the hash
+const HASH_MASK: u32 = 0x00ff_ffff;
+
+/// SYN cookies (RFC 4987 S3.6): the ISS of our SYN-ACK carries what we need to create the
+/// connection once the final ACK comes back, so half-open connections take no memory.
+///
+/// From the top, a cookie has 5 bits of a time slot counter, 3 bits indexing `MSS_TABLE`
+/// and 24 bits of a keyed hash over the quad, the whole counter and the MSS index.
+pub(crate) struct SynCookies {
+    key: [u64; 2],
+    /// where the time slots are counted from
+    epoch: Instant,
+}
+
+impl SynCookies {
+    pub(crate) fn new(key: [u64; 2], epoch: Instant) -> Self {
+        SynCookies { key, epoch }
+    }
+
+    fn slot(&self, now: Instant) -> u32 {
+        (now.saturating_duration_since(self.epoch).as_secs() / SLOT.as_secs()) as u32
+    }
+
+    fn hash(&self, local: SocketAddr, remote: SocketAddr, slot: u32, index: u8) -> u32 {
+        let mut data = Vec::with_capacity(41);
+        push_addr(&mut data, local);
+        push_addr(&mut data, remote);
+        data.extend_from_slice(&slot.to_be_bytes());
+        data.push(index);
+        siphash24(self.key, &data) as u32 & HASH_MASK
+    }
+
+    /// The cookie for a SYN from `remote` that announced `mss`, and the MSS it encodes.
+    pub(crate) fn make(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        mss: Option<u16>,
+        now: Instant,
+    ) -> (u32, u16) {
+        let mss = mss.unwrap_or(MSS_TABLE[0]);
+        // the largest one the peer can take
+        let index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u8;
+        let slot = self.slot(now);
+        let cookie = (slot % 32) << 27 | u32::from(index) << 24 | self.hash(local, remote, slot, index);
+        (cookie, MSS_TABLE[usize::from(index)])
+    }
+
+    /// The MSS encoded in `cookie` if we made it for this quad not long ago.
+    pub(crate) fn check(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        cookie: u32,
+        now: Instant,
+    ) -> Option<u16> {
+        let current = self.slot(now);
+        let index = (cookie >> 24 & 0b111) as u8;
+        [current, current.wrapping_sub(1)]
+            .into_iter()
+            .find(|slot| slot % 32 == cookie >> 27)
+            .filter(|&slot| self.hash(local, remote, slot, index) == cookie & HASH_MASK)
+            .map(|_| MSS_TABLE[usize::from(index)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const LOCAL: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80));
+    const REMOTE: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000));
+
+    #[test]
+    fn a_cookie_is_only_good_for_its_quad_and_a_while() {
+        let now = Instant::now();
+        let cookies = SynCookies::new([1, 2], now);
+        let (cookie, mss) = cookies.make(LOCAL, REMOTE, Some(1448), now);
+        assert_eq!(mss, 1440);
+        assert_eq!(cookies.check(LOCAL, REMOTE, cookie, now + SLOT), Some(1440));
+
+        assert_eq!(cookies.check(LOCAL, SocketAddr::new(REMOTE.ip(), REMOTE.port() + 1), cookie, now), None);
+        assert_eq!(cookies.check(LOCAL, REMOTE, cookie ^ 1, now), None);
+        // claiming a larger MSS breaks the hash
+        assert_eq!(cookies.check(LOCAL, REMOTE, cookie | 0b111 << 24, now), None);
+        assert_eq!(cookies.check(LOCAL, REMOTE, cookie, now + 2 * SLOT), None);
+        // nor does another key accept it
+        let other = SynCookies::new([1, 3], now);
+        assert_eq!(other.check(LOCAL, REMOTE, cookie, now), None);
+    }
+}
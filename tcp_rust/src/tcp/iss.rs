@@ -0,0 +1,70 @@
+use super::siphash::{push_addr, siphash24};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Picks initial sequence numbers the way RFC 6528 S3 asks for:
+///
+/// ```text
+/// ISS = M + F(localip, localport, remoteip, remoteport, secretkey)
+/// ```
+///
+/// where the clock M ticks every 4 microseconds and F is a keyed hash. Every quad gets a
+/// sequence space an off-path attacker can't guess, and a new incarnation of a quad still
+/// starts beyond where the old one left off, so its old duplicates don't fit.
+pub(crate) struct IssGenerator {
+    key: [u64; 2],
+    /// where M counts from
+    epoch: Instant,
+    /// the ISS of every connection instead, for deterministic tests
+    pinned: Option<u32>,
+}
+
+impl IssGenerator {
+    pub(crate) fn new(key: [u64; 2], epoch: Instant) -> Self {
+        IssGenerator {
+            key,
+            epoch,
+            pinned: None,
+        }
+    }
+
+    pub(crate) fn pin(&mut self, iss: Option<u32>) {
+        self.pinned = iss;
+    }
+
+    pub(crate) fn generate(&self, local: SocketAddr, remote: SocketAddr, now: Instant) -> u32 {
+        if let Some(iss) = self.pinned {
+            return iss;
+        }
+        // wraps around about every 4.77 hours
+        let m = (now.saturating_duration_since(self.epoch).as_micros() / 4) as u32;
+        let mut data = Vec::with_capacity(36);
+        push_addr(&mut data, local);
+        push_addr(&mut data, remote);
+        m.wrapping_add(siphash24(self.key, &data) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const LOCAL: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80));
+    const REMOTE: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000));
+
+    #[test]
+    fn each_quad_has_its_own_clock_driven_sequence_space() {
+        let now = Instant::now();
+        let mut iss = IssGenerator::new([1, 2], now);
+        let first = iss.generate(LOCAL, REMOTE, now);
+        assert_eq!(iss.generate(LOCAL, REMOTE, now + Duration::from_millis(1)), first.wrapping_add(250));
+        assert_ne!(iss.generate(LOCAL, SocketAddr::new(REMOTE.ip(), REMOTE.port() + 1), now), first);
+        assert_ne!(IssGenerator::new([1, 3], now).generate(LOCAL, REMOTE, now), first);
+
+        iss.pin(Some(7));
+        assert_eq!(iss.generate(LOCAL, REMOTE, now), 7);
+    }
+}
@@ -0,0 +1,80 @@
+use etherparse::TcpOptionElement;
+
+/// the largest shift a window scale option may ask for (RFC 7323 S2.3)
+pub(crate) const MAX_WINDOW_SCALE: u8 = 14;
+/// bytes a timestamp option takes up in every segment once negotiated, padding included
+pub(crate) const TIMESTAMP_LEN: usize = 12;
+
+/// The options of an incoming segment we understand, everything else is ignored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Options {
+    /// the largest segment the peer wants to receive, only valid on a SYN
+    pub(crate) mss: Option<u16>,
+    /// how far the peer will shift its window field, only valid on a SYN
+    pub(crate) window_scale: Option<u8>,
+    /// whether the peer can take SACK blocks, only valid on a SYN
+    pub(crate) sack_permitted: bool,
+    /// SACK blocks as `(left edge, right edge)`
+    pub(crate) sack: [Option<(u32, u32)>; 4],
+    /// TSval and TSecr
+    pub(crate) timestamp: Option<(u32, u32)>,
+}
+
+impl Options {
+    pub(crate) fn parse(tcph: &etherparse::TcpHeaderSlice) -> Self {
+        let mut options = Options::default();
+        for option in tcph.options_iterator() {
+            match option {
+                Ok(TcpOptionElement::MaximumSegmentSize(mss)) => options.mss = Some(mss),
+                Ok(TcpOptionElement::WindowScale(shift)) => {
+                    // a larger shift is treated as the maximum (RFC 7323 S2.3)
+                    options.window_scale = Some(std::cmp::min(shift, MAX_WINDOW_SCALE))
+                }
+                Ok(TcpOptionElement::SelectiveAcknowledgementPermitted) => options.sack_permitted = true,
+                Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) => {
+                    options.sack = [Some(first), rest[0], rest[1], rest[2]];
+                }
+                Ok(TcpOptionElement::Timestamp(tsval, tsecr)) => options.timestamp = Some((tsval, tsecr)),
+                Ok(_) => {}
+                // the rest can't be parsed reliably anymore
+                Err(_) => break,
+            }
+        }
+        options
+    }
+
+    pub(crate) fn sack_blocks(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.sack.iter().flatten().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_we_understand() {
+        let mut tcph = etherparse::TcpHeader::new(1, 2, 3, 4);
+        tcph.syn = true;
+        tcph.set_options(&[
+            TcpOptionElement::MaximumSegmentSize(1460),
+            TcpOptionElement::SelectiveAcknowledgementPermitted,
+            TcpOptionElement::Timestamp(7, 0),
+            TcpOptionElement::Noop,
+            TcpOptionElement::WindowScale(15),
+        ])
+        .unwrap();
+        let bytes = tcph.to_bytes();
+        let slice = etherparse::TcpHeaderSlice::from_slice(&bytes).unwrap();
+        assert_eq!(
+            Options::parse(&slice),
+            Options {
+                mss: Some(1460),
+                window_scale: Some(14),
+                sack_permitted: true,
+                sack: [None; 4],
+                timestamp: Some((7, 0)),
+            }
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use super::Connection;
+
+/// Segments that arrived ahead of RCV.NXT, held until the gap in front of them is filled.
+///
+/// Everything stored lies inside the receive window, so it is always less than 2**31
+/// ahead of RCV.NXT and sequence numbers can be compared with `wrapping_lt`.
+#[derive(Default)]
+pub(crate) struct Reassembly {
+    /// runs of data keyed by the sequence number of their first byte,
+    /// they never overlap or touch each other
+    segments: BTreeMap<u32, Vec<u8>>,
+    /// sequence number of a FIN that arrived beyond a gap
+    fin: Option<u32>,
+    /// where the segment that arrived last starts
+    last: Option<u32>,
+}
+
+impl Reassembly {
+    /// Store `data` that starts at `seq`, merging it with every run it overlaps or touches.
+    pub(crate) fn insert(&mut self, seq: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.last = Some(seq);
+        let mut start = seq;
+        let mut end = seq.wrapping_add(data.len() as u32);
+        let touching: Vec<u32> = self
+            .segments
+            .iter()
+            .filter(|&(&s, d)| {
+                !Connection::wrapping_lt(end, s)
+                    && !Connection::wrapping_lt(s.wrapping_add(d.len() as u32), start)
+            })
+            .map(|(&s, _)| s)
+            .collect();
+        for &s in &touching {
+            let e = s.wrapping_add(self.segments[&s].len() as u32);
+            if Connection::wrapping_lt(s, start) {
+                start = s;
+            }
+            if Connection::wrapping_lt(end, e) {
+                end = e;
+            }
+        }
+
+        let mut run = vec![0; end.wrapping_sub(start) as usize];
+        for s in touching {
+            let d = self.segments.remove(&s).unwrap();
+            let at = s.wrapping_sub(start) as usize;
+            run[at..at + d.len()].copy_from_slice(&d);
+        }
+        let at = seq.wrapping_sub(start) as usize;
+        run[at..at + data.len()].copy_from_slice(data);
+        self.segments.insert(start, run);
+    }
+
+    /// Up to `max` SACK blocks describing the runs we hold (RFC 2018 S4).
+    ///
+    /// The first one is the run that holds the segment that arrived last.
+    pub(crate) fn sack_blocks(&self, max: usize) -> Vec<(u32, u32)> {
+        let contains = |s: u32, d: &Vec<u8>, seq: u32| {
+            !Connection::wrapping_lt(seq, s) && Connection::wrapping_lt(seq, s.wrapping_add(d.len() as u32))
+        };
+        let latest = self.last.and_then(|seq| self.segments.iter().find(|&(&s, d)| contains(s, d, seq)));
+        latest
+            .into_iter()
+            .chain(self.segments.iter().filter(|&(&s, _)| Some(s) != latest.map(|(&s, _)| s)))
+            .take(max)
+            .map(|(&s, d)| (s, s.wrapping_add(d.len() as u32)))
+            .collect()
+    }
+
+    /// whether no data is waiting for a gap to be filled
+    pub(crate) fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Remember a FIN that arrived before the data in front of it.
+    pub(crate) fn insert_fin(&mut self, seq: u32) {
+        self.fin = Some(seq);
+    }
+
+    /// Remove the data that continues the stream at `nxt`, with the bytes before `nxt` cut off.
+    ///
+    /// Runs that are entirely before `nxt` have been received again in order and are dropped.
+    pub(crate) fn pop(&mut self, nxt: u32) -> Option<Vec<u8>> {
+        self.segments
+            .retain(|&s, d| Connection::wrapping_lt(nxt, s.wrapping_add(d.len() as u32)));
+        let start = *self
+            .segments
+            .keys()
+            .find(|&&s| !Connection::wrapping_lt(nxt, s))?;
+        let mut run = self.segments.remove(&start).unwrap();
+        run.drain(..nxt.wrapping_sub(start) as usize);
+        Some(run)
+    }
+
+    /// Whether the stream has reached a FIN that arrived out of order, forgetting it if so.
+    pub(crate) fn take_fin(&mut self, nxt: u32) -> bool {
+        if self.fin == Some(nxt) {
+            self.fin = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases_data_once_the_gap_is_filled() {
+        let mut r = Reassembly::default();
+        r.insert(10, b"klm");
+        r.insert(5, b"fgh");
+        assert_eq!(r.pop(0), None);
+        assert_eq!(r.segments.len(), 2);
+
+        // "ij" closes the gap between the two runs
+        r.insert(8, b"ij");
+        assert_eq!(r.pop(5).as_deref(), Some(&b"fghijklm"[..]));
+        assert!(r.segments.is_empty());
+    }
+
+    #[test]
+    fn merges_overlapping_segments() {
+        let mut r = Reassembly::default();
+        r.insert(4, b"efgh");
+        r.insert(2, b"cdef");
+        r.insert(6, b"ghij");
+        assert_eq!(r.segments.len(), 1);
+        // the first bytes of the run were delivered in order in the meantime
+        assert_eq!(r.pop(3).as_deref(), Some(&b"defghij"[..]));
+    }
+
+    #[test]
+    fn drops_data_that_was_received_in_order_meanwhile() {
+        let mut r = Reassembly::default();
+        r.insert(4, b"ef");
+        r.insert(10, b"kl");
+        assert_eq!(r.pop(8), None);
+        assert_eq!(r.segments.keys().collect::<Vec<_>>(), [&10]);
+    }
+
+    #[test]
+    fn reports_the_latest_run_first() {
+        let mut r = Reassembly::default();
+        r.insert(10, b"kl");
+        r.insert(20, b"uv");
+        r.insert(30, b"ef");
+        r.insert(21, b"vw");
+        assert_eq!(r.sack_blocks(4), [(20, 23), (10, 12), (30, 32)]);
+        assert_eq!(r.sack_blocks(2), [(20, 23), (10, 12)]);
+    }
+
+    #[test]
+    fn handles_sequence_number_wraparound() {
+        let mut r = Reassembly::default();
+        let nxt = u32::MAX - 2;
+        r.insert(1, b"ef");
+        r.insert(nxt.wrapping_add(1), b"bcd");
+        assert_eq!(r.pop(nxt), None);
+        r.insert(nxt, b"a");
+        assert_eq!(r.pop(nxt).as_deref(), Some(&b"abcdef"[..]));
+    }
+}
@@ -0,0 +1,142 @@
+use super::Connection;
+
+/// how many SACKed segments above a hole make it count as lost (RFC 6675 S2)
+const DUP_THRESH: usize = 3;
+
+/// What the receiver told us in SACK blocks that it holds beyond SND.UNA (RFC 6675 S3).
+///
+/// Everything in here lies between SND.UNA and SND.NXT, so sequence numbers can be
+/// compared with `wrapping_lt`.
+#[derive(Default)]
+pub(crate) struct Scoreboard {
+    /// SACKed ranges as `(start, end)`, in order, they never overlap or touch each other
+    sacked: Vec<(u32, u32)>,
+}
+
+impl Scoreboard {
+    pub(crate) fn clear(&mut self) {
+        self.sacked.clear();
+    }
+
+    /// Mark `start..end` as received, unless it lies outside of `una..nxt`, where a SACK
+    /// block is either stale or bogus.
+    pub(crate) fn insert(&mut self, una: u32, nxt: u32, (mut start, mut end): (u32, u32)) {
+        if !Connection::wrapping_lt(start, end)
+            || Connection::wrapping_lt(start, una)
+            || Connection::wrapping_lt(nxt, end)
+        {
+            return;
+        }
+        let mut i = 0;
+        while i < self.sacked.len() {
+            let (s, e) = self.sacked[i];
+            if Connection::wrapping_lt(e, start) {
+                i += 1;
+            } else if Connection::wrapping_lt(end, s) {
+                break;
+            } else {
+                // overlaps or touches, merge it
+                if Connection::wrapping_lt(s, start) {
+                    start = s;
+                }
+                if Connection::wrapping_lt(end, e) {
+                    end = e;
+                }
+                self.sacked.remove(i);
+            }
+        }
+        self.sacked.insert(i, (start, end));
+    }
+
+    /// Forget what the cumulative ACK covers now.
+    pub(crate) fn advance(&mut self, una: u32) {
+        self.sacked.retain(|&(_, e)| Connection::wrapping_lt(una, e));
+        if let Some(first) = self.sacked.first_mut() {
+            if Connection::wrapping_lt(first.0, una) {
+                first.0 = una;
+            }
+        }
+    }
+
+    /// the first SACKed byte at or after `seq`, a retransmission from `seq` stops there
+    pub(crate) fn next_sacked(&self, seq: u32) -> Option<u32> {
+        self.sacked
+            .iter()
+            .find(|&&(_, e)| Connection::wrapping_lt(seq, e))
+            .map(|&(s, _)| if Connection::wrapping_lt(s, seq) { seq } else { s })
+    }
+
+    /// the end of the highest SACKed range
+    pub(crate) fn high(&self) -> Option<u32> {
+        self.sacked.last().map(|&(_, e)| e)
+    }
+
+    /// IsLost() of RFC 6675 S4: enough was SACKed above `seq` that it can't just be reordered.
+    pub(crate) fn is_lost(&self, seq: u32, smss: usize) -> bool {
+        let mut bytes = 0;
+        let mut segments = 0;
+        for &(s, e) in self.sacked.iter().rev() {
+            if !Connection::wrapping_lt(seq, e) {
+                break;
+            }
+            let s = if Connection::wrapping_lt(s, seq) { seq } else { s };
+            let len = e.wrapping_sub(s) as usize;
+            bytes += len;
+            segments += len.div_ceil(smss);
+        }
+        segments >= DUP_THRESH || bytes > (DUP_THRESH - 1) * smss
+    }
+
+    /// The ranges of `una..nxt` that are not SACKed, in order.
+    pub(crate) fn holes(&self, una: u32, nxt: u32) -> Vec<(u32, u32)> {
+        let mut holes = Vec::with_capacity(self.sacked.len() + 1);
+        let mut at = una;
+        for &(s, e) in &self.sacked {
+            if Connection::wrapping_lt(at, s) {
+                holes.push((at, s));
+            }
+            at = e;
+        }
+        if Connection::wrapping_lt(at, nxt) {
+            holes.push((at, nxt));
+        }
+        holes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_blocks_and_finds_the_holes() {
+        let mut sb = Scoreboard::default();
+        sb.insert(100, 1000, (300, 400));
+        sb.insert(100, 1000, (600, 700));
+        sb.insert(100, 1000, (400, 500));
+        // outside of the window
+        sb.insert(100, 1000, (50, 150));
+        sb.insert(100, 1000, (900, 1100));
+        assert_eq!(sb.sacked, [(300, 500), (600, 700)]);
+        assert_eq!(sb.holes(100, 1000), [(100, 300), (500, 600), (700, 1000)]);
+        assert_eq!(sb.next_sacked(100), Some(300));
+        assert_eq!(sb.next_sacked(350), Some(350));
+        assert_eq!(sb.next_sacked(700), None);
+
+        sb.advance(350);
+        assert_eq!(sb.sacked, [(350, 500), (600, 700)]);
+        assert_eq!(sb.holes(350, 1000), [(500, 600), (700, 1000)]);
+    }
+
+    #[test]
+    fn a_hole_is_lost_below_three_sacked_segments() {
+        let mut sb = Scoreboard::default();
+        sb.insert(0, 1000, (100, 200));
+        sb.insert(0, 1000, (300, 400));
+        assert!(!sb.is_lost(0, 100));
+        sb.insert(0, 1000, (500, 600));
+        assert!(sb.is_lost(0, 100));
+        // only two above the second hole
+        assert!(!sb.is_lost(200, 100));
+    }
+}
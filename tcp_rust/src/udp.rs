@@ -0,0 +1,290 @@
+//! UDP (RFC 768) sockets on the same interface as the TCP connections.
+//!
+//! Like TCP data, datagrams are only queued by the user's thread; the packet thread sends
+//! them on its next tick, and queues those it receives for `recv_from`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use etherparse::icmpv4::DestUnreachableHeader;
+use etherparse::{IpSlice, PacketBuilder, UdpSlice};
+
+use crate::device::PacketDevice;
+use crate::{check_timeout, icmp, tcp, wait_until, ConnectionManager, Interface, InterfaceHandle, TcpHandle};
+
+/// a UDP header
+const UDP_HEADER_LEN: usize = 8;
+
+/// the state of a bound UDP port
+#[derive(Default)]
+pub(crate) struct Endpoint {
+    /// datagrams not read yet, with their senders
+    incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// bytes in `incoming`, datagrams beyond `RECV_BUFFER_SIZE` are dropped
+    incoming_len: usize,
+    /// datagrams for the packet thread to send: source, destination and payload
+    outgoing: VecDeque<(SocketAddr, SocketAddr, Vec<u8>)>,
+    /// bytes in `outgoing`, `send_to` waits beyond `SENDQUEUE_SIZE`
+    outgoing_len: usize,
+    /// set by `connect`: datagrams only come from here, and `send` goes here
+    peer: Option<SocketAddr>,
+    read_timeout: Option<Duration>,
+    /// `recv_from` and `send_to` fail with `WouldBlock` instead of waiting
+    nonblocking: bool,
+    /// the socket was dropped, the port is freed once `outgoing` went out
+    detached: bool,
+}
+
+pub struct UdpSocket {
+    port: u16,
+    /// local addresses of the interface, to send from
+    addr: Ipv4Addr,
+    addr6: Option<Ipv6Addr>,
+    ih: InterfaceHandle,
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        // don't panic again if a panic poisoned the lock
+        let Ok(mut cm) = self.ih.manager.lock() else {
+            return;
+        };
+        let Some(e) = cm.udp.get_mut(&self.port) else {
+            return;
+        };
+        if e.outgoing.is_empty() {
+            cm.udp.remove(&self.port);
+        } else {
+            // what was sent still goes out, on_tick forgets the port afterwards
+            e.detached = true;
+            e.incoming.clear();
+        }
+    }
+}
+
+impl UdpSocket {
+    /// Take datagrams to `port` of any of the interface's addresses; port 0 picks a free
+    /// ephemeral one, see `local_addr`.
+    pub fn bind(iface: &mut Interface, port: u16) -> io::Result<UdpSocket> {
+        let ih = iface.ih.as_ref().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        let port = match port {
+            0 => cm.ephemeral_udp_port()?,
+            port if cm.udp.contains_key(&port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "port already bound",
+                ));
+            }
+            port => port,
+        };
+        cm.udp.insert(port, Endpoint::default());
+        Ok(UdpSocket {
+            port,
+            addr: iface.addr,
+            addr6: iface.addr6,
+            ih: ih.clone(),
+        })
+    }
+
+    /// The address datagrams to the peer go out from once connected, of the peer's IP
+    /// version; before that the interface's IPv4 address, though IPv6 is taken as well.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let cm = self.ih.manager.lock().unwrap();
+        let ip = match cm.udp[&self.port].peer {
+            Some(peer) => crate::local_addr(self.addr, self.addr6, peer.ip())?,
+            None => self.addr.into(),
+        };
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    /// Only take datagrams from `peer` from now on, and make it where `send` goes.
+    pub fn connect(&self, peer: impl Into<SocketAddr>) -> io::Result<()> {
+        let peer = peer.into();
+        // fail here rather than on every send
+        crate::local_addr(self.addr, self.addr6, peer.ip())?;
+        let mut cm = self.ih.manager.lock().unwrap();
+        let e = cm.udp.get_mut(&self.port).expect("port closed while socket still active");
+        e.peer = Some(peer);
+        // nor those that arrived before
+        e.incoming.retain(|(from, _)| *from == peer);
+        e.incoming_len = e.incoming.iter().map(|(_, data)| data.len()).sum();
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let cm = self.ih.manager.lock().unwrap();
+        cm.udp[&self.port].peer.ok_or_else(not_connected)
+    }
+
+    /// Queue `buf` as one datagram to `target`. It has to fit in the MTU, as we don't
+    /// fragment; waits while the send queue is full.
+    pub fn send_to(&self, buf: &[u8], target: impl Into<SocketAddr>) -> io::Result<usize> {
+        let target = target.into();
+        let source = SocketAddr::new(crate::local_addr(self.addr, self.addr6, target.ip())?, self.port);
+        let ip_header_len = match target {
+            SocketAddr::V4(_) => etherparse::Ipv4Header::MIN_LEN,
+            SocketAddr::V6(_) => etherparse::Ipv6Header::LEN,
+        };
+        if buf.len() > tcp::MTU - ip_header_len - UDP_HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram too large for the MTU",
+            ));
+        }
+
+        let mut cm = self.ih.manager.lock().unwrap();
+        loop {
+            let e = cm.udp.get_mut(&self.port).expect("port closed while socket still active");
+            if e.outgoing_len + buf.len() <= crate::SENDQUEUE_SIZE {
+                e.outgoing.push_back((source, target, buf.to_vec()));
+                e.outgoing_len += buf.len();
+                return Ok(buf.len());
+            }
+            if e.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            }
+            // the next tick empties the queue
+            cm = self.ih.snd_var.wait(cm).unwrap();
+        }
+    }
+
+    /// `send_to` the peer given to `connect`
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to(buf, self.peer_addr()?)
+    }
+
+    /// Wait for a datagram and copy it into `buf`, what doesn't fit is discarded.
+    /// Returns its length in `buf` and who sent it.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        let e = &cm.udp[&self.port];
+        let (nonblocking, deadline) = (e.nonblocking, e.read_timeout.map(|t| Instant::now() + t));
+        loop {
+            let e = cm.udp.get_mut(&self.port).expect("port closed while socket still active");
+            if let Some((from, data)) = e.incoming.pop_front() {
+                e.incoming_len -= data.len();
+                let n = std::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok((n, from));
+            }
+            if nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no datagram received yet",
+                ));
+            }
+            cm = wait_until(&self.ih.rcv_var, cm, deadline)?;
+        }
+    }
+
+    /// `recv_from` the peer given to `connect`
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.peer_addr()?;
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+
+    /// Make `recv_from` and `recv` fail with `TimedOut` once they waited `timeout` for a
+    /// datagram, `None` waits forever, which is the default. A zero timeout is `InvalidInput`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.udp.get_mut(&self.port).expect("port closed while socket still active").read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        let cm = self.ih.manager.lock().unwrap();
+        Ok(cm.udp[&self.port].read_timeout)
+    }
+
+    /// Make receiving, and sending while the send queue is full, fail with `WouldBlock`
+    /// instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.udp.get_mut(&self.port).expect("port closed while socket still active").nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "socket is not connected")
+}
+
+/// Queue a UDP datagram that arrived in `ip` on the socket bound to its port, or tell the
+/// sender that nobody is (RFC 1122 S4.1.3.1).
+pub(crate) fn on_udp(ih: &TcpHandle, nic: &mut dyn PacketDevice, ip: &IpSlice) -> io::Result<()> {
+    let Ok(udp) = UdpSlice::from_slice(ip.payload().payload) else {
+        return Ok(());
+    };
+    let header = udp.to_header();
+    let checksum = match ip {
+        // zero means the sender didn't compute one, which only IPv4 allows (RFC 8200 S8.1)
+        IpSlice::Ipv4(_) if header.checksum == 0 => Ok(0),
+        IpSlice::Ipv4(v4) => header.calc_checksum_ipv4(&v4.header().to_header(), udp.payload()),
+        IpSlice::Ipv6(v6) => header.calc_checksum_ipv6(&v6.header().to_header(), udp.payload()),
+    };
+    if checksum.ok() != Some(header.checksum) {
+        return Ok(());
+    }
+
+    let from = SocketAddr::new(ip.source_addr(), udp.source_port());
+    let mut cm = ih.manager.lock().unwrap();
+    match cm.udp.get_mut(&udp.destination_port()) {
+        Some(e) if !e.detached => {
+            if e.peer.is_some_and(|peer| peer != from) {
+                return Ok(());
+            }
+            if e.incoming_len + udp.payload().len() > crate::RECV_BUFFER_SIZE {
+                return Ok(());
+            }
+            e.incoming.push_back((from, udp.payload().to_vec()));
+            e.incoming_len += udp.payload().len();
+            drop(cm);
+            ih.rcv_var.notify_all();
+        }
+        _ => {
+            drop(cm);
+            if let IpSlice::Ipv4(v4) = ip {
+                icmp::send_unreachable(nic, v4, DestUnreachableHeader::Port)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send the queued datagrams of every socket and forget the dropped ones, true if any
+/// left a queue.
+///
+/// UDP is best-effort: a datagram the nic won't take is lost, and the TCP connections
+/// sharing the nic go on.
+pub(crate) fn on_tick(cm: &mut ConnectionManager, nic: &mut dyn PacketDevice) -> bool {
+    let mut sent = false;
+    for e in cm.udp.values_mut() {
+        while let Some((from, to, data)) = e.outgoing.pop_front() {
+            e.outgoing_len -= data.len();
+            let _ = send(nic, from, to, &data);
+            sent = true;
+        }
+    }
+    cm.udp.retain(|_, e| !e.detached);
+    sent
+}
+
+fn send(nic: &mut dyn PacketDevice, from: SocketAddr, to: SocketAddr, payload: &[u8]) -> io::Result<()> {
+    let builder = match (from.ip(), to.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => PacketBuilder::ipv4(src.octets(), dst.octets(), 64),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => PacketBuilder::ipv6(src.octets(), dst.octets(), 64),
+        _ => panic!("{} and {} are not of the same IP version", from, to),
+    }
+    .udp(from.port(), to.port());
+    let mut buf = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut buf, payload).map_err(io::Error::other)?;
+    nic.send(&buf)?;
+    Ok(())
+}